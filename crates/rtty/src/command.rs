@@ -1,111 +1,194 @@
-use std::{pin::Pin, sync::Arc};
+use std::{fmt, pin::Pin, time::Duration};
 
-use async_stream::stream;
 use bytes::Bytes;
 use futures_util::{Sink, Stream};
-use pty_process::Size;
-use tokio::{io::AsyncWriteExt, sync::Notify};
-use tokio_stream::StreamExt;
-use tokio_util::{io::ReaderStream, sync::PollSendError};
-use tracing::{debug, error};
+use tokio_util::sync::PollSendError;
+
+use crate::OverflowPolicy;
+
+#[cfg(unix)]
+mod unix;
+#[cfg(windows)]
+mod windows;
+
+#[cfg(unix)]
+pub use unix::{Command, start_command};
+#[cfg(unix)]
+pub(crate) use unix::{configure, spawn};
+#[cfg(windows)]
+pub use windows::{Command, start_command};
+#[cfg(windows)]
+pub(crate) use windows::{configure, spawn};
+
+/// Terminal dimensions in character cells.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Size {
+    pub rows: u16,
+    pub cols: u16,
+}
+
+impl Size {
+    /// Largest number of rows or columns a PTY is given.
+    pub const MAX_DIMENSION: u16 = 1000;
+
+    pub fn new(rows: u16, cols: u16) -> Self {
+        Self { rows, cols }
+    }
+
+    /// Limits both dimensions to `1..=MAX_DIMENSION`.
+    pub fn clamped(self) -> Self {
+        Self {
+            rows: self.rows.clamp(1, Self::MAX_DIMENSION),
+            cols: self.cols.clamp(1, Self::MAX_DIMENSION),
+        }
+    }
+}
+
+/// How a command came to an end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandExit {
+    /// The command exited on its own. A command killed by a signal reports
+    /// `128 + signal`, as shells do.
+    Exited(i32),
+    /// The command was stopped through the aborter.
+    Aborted,
+}
+
+impl fmt::Display for CommandExit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Exited(code) => write!(f, "Command exited with status code: {code}"),
+            Self::Aborted => f.write_str("Aborted"),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub enum CommandOutputItem {
     Output(Bytes),
     Error(String),
-    Exit(String),
+    Exit(CommandExit),
+    /// The window title was set with an OSC 0/2 sequence. Only emitted when
+    /// [`CommandOptions::detect_title`] is set; the sequence itself is still
+    /// passed through in [`CommandOutputItem::Output`].
+    Title(String),
+    /// The terminal bell (BEL) was rung. Only emitted when
+    /// [`CommandOptions::detect_bell`] is set; the byte itself is still
+    /// passed through in [`CommandOutputItem::Output`].
+    Bell,
+    /// The PTY was resized following a [`CommandInputItem::Resize`], to the
+    /// given size after clamping it with [`Size::clamped`].
+    Resized(Size),
 }
 
 #[derive(Debug)]
 pub enum CommandInputItem {
+    /// Raw bytes, written as-is. Use this for input that may split a
+    /// multi-byte UTF-8 character across items.
     Input(Vec<u8>),
+    /// Text, written as its UTF-8 bytes. Being a `String` it always holds
+    /// whole characters, so a sender that chunks text must split it on
+    /// character boundaries (or send [`CommandInputItem::Input`] instead).
     InputString(String),
+    /// Resizes the PTY. The size is clamped with [`Size::clamped`] and the
+    /// result reported as [`CommandOutputItem::Resized`].
     Resize(Size),
+    /// Signals end of input by writing the terminal's VEOF character, as if
+    /// the user pressed Ctrl-D (Ctrl-Z on Windows). Like Ctrl-D it only ends
+    /// input at the start of a line; mid-line it just flushes the pending
+    /// line.
+    Eof,
 }
 
-pub fn start_command(
-    command: pty_process::Command,
-    aborter: Arc<Notify>,
-    size: Option<Size>,
-) -> Result<
-    (
-        Pin<Box<dyn Stream<Item = CommandOutputItem> + Send>>,
-        Pin<Box<dyn Sink<CommandInputItem, Error = PollSendError<CommandInputItem>> + Send>>,
-    ),
-    pty_process::Error,
-> {
-    let (pty, pts) = pty_process::open()?;
-
-    if let Some(size) = size {
-        pty.resize(size).ok();
-    }
+pub type CommandOutputStream = Pin<Box<dyn Stream<Item = CommandOutputItem> + Send>>;
+pub type CommandInputSink =
+    Pin<Box<dyn Sink<CommandInputItem, Error = PollSendError<CommandInputItem>> + Send>>;
 
-    let mut child = command.spawn(pts)?;
-    let (pty_out, mut pty_in) = pty.into_split();
-    let mut out_stream = ReaderStream::new(pty_out);
-    let exited = Arc::new(Notify::new());
-    let exited_clone = exited.clone();
-
-    let stream = futures_util::StreamExt::boxed(stream! {
-        loop {
-            tokio::select! {
-                Some(output) = out_stream.next() =>
-                    match output {
-                        Ok(b) => yield CommandOutputItem::Output(b.into()),
-                        // workaround against PTY closing incorrect error handling
-                        // see: https://stackoverflow.com/questions/72150987/why-does-reading-from-an-exited-pty-process-return-input-output-error-in-rust
-                        Err(err) if err.to_string() == "Input/output error (os error 5)" => continue,
-                        Err(err) => yield CommandOutputItem::Error(err.to_string()),
-                    },
-                status = child.wait() => {
-                    match status {
-                        Err(err) => yield CommandOutputItem::Error(err.to_string()),
-                        Ok(status) => {
-                            let code = status.code().unwrap_or(0);
-                            yield CommandOutputItem::Exit(format!("Command exited with status code: {code}"));
-                            exited_clone.notify_waiters();
-                            break;
-                        }
-                    }
-                },
-                _ = aborter.notified() => {
-                    match child.start_kill() {
-                        Ok(()) => debug!("Command aborted"),
-                        Err(err) => error!("Failed to abort command: {err}"),
-                    };
-                    yield CommandOutputItem::Exit("Aborted".to_string());
-                    exited_clone.notify_waiters();
-                    break;
-                }
-            }
-        }
-    });
-
-    let (input_tx, mut input_rx) = tokio::sync::mpsc::channel::<CommandInputItem>(200);
-    let input_sink = Box::pin(tokio_util::sync::PollSender::new(input_tx));
-
-    tokio::spawn(async move {
-        loop {
-            tokio::select! {
-              Some(input) = input_rx.recv() => {
-                match input {
-                  CommandInputItem::Input(input) => {
-                    pty_in.write(&input).await.unwrap();
-                  }
-                  CommandInputItem::InputString(input) => {
-                    pty_in.write(input.as_bytes()).await.unwrap();
-                  }
-                  CommandInputItem::Resize(size) => {
-                    pty_in.resize(size).ok();
-                  }
-                }
-              }
-              _ = exited.notified() => {
-                  break;
-              }
-            }
-        }
-    });
+/// A freshly spawned command, as returned by the platform `spawn`.
+pub(crate) struct Spawned {
+    pub output: CommandOutputStream,
+    pub input: CommandInputSink,
+    pub pid: Option<u32>,
+}
 
-    Ok((stream, input_sink))
+/// Flow control configured on the PTY.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlowControl {
+    /// Ctrl-S and Ctrl-Q are passed to the command like any other input.
+    None,
+    /// XON/XOFF: Ctrl-S pauses the command's output until Ctrl-Q resumes it,
+    /// and neither reaches the command.
+    Software,
+}
+
+/// What to do with input the command won't take within
+/// [`CommandOptions::write_timeout`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteTimeoutPolicy {
+    /// Discard the rest of the input and carry on with the next.
+    Drop,
+    /// Stop the command as if the aborter had been notified.
+    Abort,
+}
+
+#[derive(Debug, Clone)]
+pub struct CommandOptions {
+    /// Initial PTY size, clamped with [`Size::clamped`].
+    pub size: Option<Size>,
+    /// Time an aborted command is given to exit after SIGTERM before it is
+    /// sent SIGKILL. Windows has no SIGTERM, so commands are killed at once.
+    pub kill_grace: Duration,
+    /// Capacity of the input channel feeding the PTY. Once it is full,
+    /// sending on the input sink waits until the PTY writer catches up, which
+    /// back-pressures whoever is feeding it (e.g. the WebSocket reader).
+    pub input_buffer: usize,
+    /// Scan output for window title changes and emit
+    /// [`CommandOutputItem::Title`] events.
+    pub detect_title: bool,
+    /// Scan output for BEL characters and emit [`CommandOutputItem::Bell`]
+    /// events.
+    pub detect_bell: bool,
+    /// Caps output read from the PTY but not yet consumed at the given
+    /// number of bytes, handling overflow with the given policy. `None`
+    /// reads the PTY only as fast as the output stream is consumed.
+    pub output_limit: Option<(usize, OverflowPolicy)>,
+    /// Flow control set on the PTY before the command starts. `None` keeps
+    /// the system default (XON/XOFF on most Unix systems). Ignored on
+    /// Windows, which has no termios.
+    pub flow_control: Option<FlowControl>,
+    /// Make the PTY the command's controlling terminal, so that e.g. Ctrl-C
+    /// sends SIGINT to its foreground process group and shells can do job
+    /// control. Without it the command still sees a terminal on its stdio,
+    /// but typed control characters reach it as plain input, and resizes are
+    /// announced by sending SIGWINCH to its process group. Replaces any
+    /// pre-exec hook set on the command when off. Ignored on Windows.
+    pub controlling_terminal: bool,
+    /// Keep descriptors this process holds open without close-on-exec (e.g.
+    /// ones inherited from its own parent) from leaking into the command, so
+    /// it only gets the PTS. Replaces any pre-exec hook set on the command
+    /// when on. Ignored on Windows.
+    pub close_fds: bool,
+    /// Gives up on writing a piece of input to the PTY once it has blocked
+    /// for the given time, e.g. because the command stopped reading and the
+    /// terminal's buffer is full, handling it with the given policy. `None`
+    /// waits for as long as it takes, holding up all input behind it.
+    /// Ignored on Windows, where writes run on their own thread.
+    pub write_timeout: Option<(Duration, WriteTimeoutPolicy)>,
+}
+
+impl Default for CommandOptions {
+    fn default() -> Self {
+        Self {
+            size: None,
+            kill_grace: Duration::from_secs(5),
+            input_buffer: 200,
+            detect_title: false,
+            detect_bell: false,
+            output_limit: None,
+            flow_control: None,
+            controlling_terminal: true,
+            close_fds: true,
+            write_timeout: None,
+        }
+    }
 }
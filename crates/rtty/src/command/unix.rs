@@ -0,0 +1,789 @@
+use std::{
+    ffi::OsString,
+    os::{fd::AsRawFd, unix::process::ExitStatusExt},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use async_stream::stream;
+use bytes::{Bytes, BytesMut};
+use futures_util::Stream;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::Notify,
+    time::Instant,
+};
+use tokio_stream::StreamExt;
+use tracing::{Instrument, debug, error, warn};
+
+use crate::{
+    CommandExit, CommandInputItem, CommandInputSink, CommandOptions, CommandOutputItem,
+    CommandOutputStream, Error, FlowControl, OutputScanner, Size, Spawned, WriteTimeoutPolicy,
+    bounded_output,
+};
+
+pub type Command = pty_process::Command;
+
+impl From<Size> for pty_process::Size {
+    fn from(size: Size) -> Self {
+        Self::new(size.rows, size.cols)
+    }
+}
+
+/// Longest time output left in the PTY when the command exits is read for
+/// before the exit is reported, in case processes it left behind keep writing.
+const OUTPUT_DRAIN_TIME: Duration = Duration::from_millis(100);
+
+/// Spawns `command` on a new PTY.
+///
+/// The command leads a new session and process group, so everything it
+/// starts can be signalled together. When `aborter` is notified the group
+/// receives SIGTERM and the command is given `options.kill_grace` to exit on
+/// its own before the group is sent SIGKILL. Processes left in the group
+/// when an aborted command exits are killed straight away.
+pub fn start_command(
+    command: Command,
+    aborter: Arc<Notify>,
+    options: CommandOptions,
+) -> Result<(CommandOutputStream, CommandInputSink), Error> {
+    spawn(command, aborter, options).map(|spawned| (spawned.output, spawned.input))
+}
+
+/// Like [`start_command`], also returning the child's process id.
+pub(crate) fn spawn(
+    command: Command,
+    aborter: Arc<Notify>,
+    options: CommandOptions,
+) -> Result<Spawned, Error> {
+    let CommandOptions {
+        size,
+        kill_grace,
+        input_buffer,
+        detect_title,
+        detect_bell,
+        output_limit,
+        flow_control,
+        controlling_terminal,
+        close_fds,
+        write_timeout,
+    } = options;
+    let (pty, pts) = pty_process::open()?;
+
+    if let Some(size) = size {
+        pty.resize(size.clamped().into()).ok();
+    }
+
+    if let Some(flow_control) = flow_control {
+        set_flow_control(pty.as_raw_fd(), flow_control)?;
+    }
+
+    if !controlling_terminal {
+        // with nobody to signal, Ctrl-C and friends are passed on as input
+        disable_signal_chars(pty.as_raw_fd())?;
+    }
+
+    let command = if controlling_terminal && !close_fds {
+        command
+    } else {
+        // looked up before forking; sysconf() isn't async-signal-safe
+        let open_max = unsafe { libc::sysconf(libc::_SC_OPEN_MAX) };
+        let hook = move || {
+            if !controlling_terminal {
+                release_controlling_terminal()?;
+            }
+            if close_fds {
+                close_inherited_fds(open_max);
+            }
+            Ok(())
+        };
+        // SAFETY: the hook only makes async-signal-safe calls
+        unsafe { command.pre_exec(hook) }
+    };
+    let mut child = command.spawn(pts)?;
+    let pid = child.id();
+    // spawning runs setsid(), so the child leads its own process group
+    let pgid = pid.and_then(|pid| libc::pid_t::try_from(pid).ok());
+    // stays valid for as long as `pty_in` holds the PTY open
+    let pty_fd = pty.as_raw_fd();
+    let (pty_out, mut pty_in) = pty.into_split();
+    let mut out_stream = Box::pin(read_output(pty_out));
+    let exited = Arc::new(Notify::new());
+    let exited_clone = exited.clone();
+    let (resized_tx, mut resized_rx) = tokio::sync::mpsc::unbounded_channel();
+    let input_aborter = aborter.clone();
+
+    let mut scanner =
+        (detect_title || detect_bell).then(|| OutputScanner::new(detect_title, detect_bell));
+
+    let stream = futures_util::StreamExt::boxed(stream! {
+        let mut aborted = false;
+        let mut killed = false;
+        let kill_timer = tokio::time::sleep(kill_grace);
+        tokio::pin!(kill_timer);
+        loop {
+            tokio::select! {
+                Some(output) = out_stream.next() =>
+                    match output {
+                        Ok(b) => {
+                            let events = scanner.as_mut().map(|s| s.feed(&b)).unwrap_or_default();
+                            yield CommandOutputItem::Output(b);
+                            for event in events {
+                                yield event;
+                            }
+                        }
+                        // reading a PTY whose other side was closed fails instead of returning EOF
+                        // see: https://stackoverflow.com/questions/72150987/why-does-reading-from-an-exited-pty-process-return-input-output-error-in-rust
+                        Err(err) if is_pty_closed(&err) => continue,
+                        Err(err) => yield CommandOutputItem::Error(err.to_string()),
+                    },
+                Some(size) = resized_rx.recv() => yield CommandOutputItem::Resized(size),
+                status = child.wait() => {
+                    match status {
+                        Err(err) => yield CommandOutputItem::Error(err.to_string()),
+                        Ok(_) if aborted => {
+                            // don't leave behind children that survived SIGTERM
+                            signal_group(pgid, libc::SIGKILL).ok();
+                            yield CommandOutputItem::Exit(CommandExit::Aborted);
+                            exited_clone.notify_waiters();
+                            break;
+                        }
+                        Ok(status) => {
+                            // output written just before exiting may still be waiting in the PTY
+                            let drained = Instant::now() + OUTPUT_DRAIN_TIME;
+                            while let Ok(Some(Ok(b))) = tokio::time::timeout_at(drained, out_stream.next()).await {
+                                let events = scanner.as_mut().map(|s| s.feed(&b)).unwrap_or_default();
+                                yield CommandOutputItem::Output(b);
+                                for event in events {
+                                    yield event;
+                                }
+                            }
+                            let code = status
+                                .code()
+                                .or_else(|| status.signal().map(|signal| 128 + signal))
+                                .unwrap_or(0);
+                            yield CommandOutputItem::Exit(CommandExit::Exited(code));
+                            exited_clone.notify_waiters();
+                            break;
+                        }
+                    }
+                },
+                _ = aborter.notified(), if !aborted => {
+                    aborted = true;
+                    match signal_group(pgid, libc::SIGTERM) {
+                        Ok(()) => debug!("Command aborted, waiting {kill_grace:?} before killing"),
+                        Err(err) => error!("Failed to terminate command: {err}"),
+                    };
+                    kill_timer.as_mut().reset(Instant::now() + kill_grace);
+                },
+                () = &mut kill_timer, if aborted && !killed => {
+                    killed = true;
+                    match signal_group(pgid, libc::SIGKILL) {
+                        Ok(()) => debug!("Command did not exit within grace period, killed"),
+                        Err(err) => error!("Failed to kill command: {err}"),
+                    };
+                }
+            }
+        }
+    });
+
+    let (input_tx, mut input_rx) = tokio::sync::mpsc::channel::<CommandInputItem>(input_buffer);
+    let input_sink = Box::pin(tokio_util::sync::PollSender::new(input_tx));
+
+    tokio::spawn(
+        async move {
+            let mut aborted = false;
+            loop {
+                tokio::select! {
+                  Some(input) = input_rx.recv() => {
+                    if aborted {
+                        // keep taking input so senders aren't stalled until the command is gone
+                        continue;
+                    }
+                    let written = match input {
+                      CommandInputItem::Input(input) => {
+                        write_input(&mut pty_in, &input, write_timeout).await
+                      }
+                      CommandInputItem::InputString(input) => {
+                        write_input(&mut pty_in, input.as_bytes(), write_timeout).await
+                      }
+                      CommandInputItem::Resize(size) => {
+                        let size = size.clamped();
+                        if pty_in.resize(size.into()).is_ok() {
+                            // the kernel only signals the foreground group of a
+                            // controlling terminal, so without one do it here
+                            if !controlling_terminal {
+                                signal_group(pgid, libc::SIGWINCH).ok();
+                            }
+                            resized_tx.send(size).ok();
+                        }
+                        Ok(true)
+                      }
+                      CommandInputItem::Eof => {
+                        write_input(&mut pty_in, &[eof_char(pty_fd)], write_timeout).await
+                      }
+                    };
+                    match written {
+                        Ok(true) => {}
+                        Ok(false) => {
+                            if write_timeout.is_some_and(|(_, policy)| policy == WriteTimeoutPolicy::Abort) {
+                                aborted = true;
+                                input_aborter.notify_one();
+                            }
+                        }
+                        Err(err) => {
+                            error!("Failed to write to command: {err}");
+                            break;
+                        }
+                    }
+                  }
+                  _ = exited.notified() => {
+                      break;
+                  }
+                }
+            }
+        }
+        .in_current_span(),
+    );
+
+    let stream = match output_limit {
+        Some((limit, policy)) => bounded_output(stream, limit, policy),
+        None => stream,
+    };
+
+    Ok(Spawned {
+        output: stream,
+        input: input_sink,
+        pid,
+    })
+}
+
+/// Reads output from the PTY until it is closed or fails, retrying reads
+/// interrupted by a signal.
+fn read_output(
+    mut pty_out: pty_process::OwnedReadPty,
+) -> impl Stream<Item = std::io::Result<Bytes>> {
+    const CHUNK_SIZE: usize = 4096;
+    stream! {
+        let mut buf = BytesMut::new();
+        loop {
+            buf.reserve(CHUNK_SIZE);
+            match pty_out.read_buf(&mut buf).await {
+                Ok(0) => break,
+                Ok(_) => yield Ok(buf.split().freeze()),
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    yield Err(err);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Whether a failed PTY read means the command's side of the terminal was
+/// closed, i.e. the output has ended, which Linux reports as EIO.
+fn is_pty_closed(err: &std::io::Error) -> bool {
+    err.raw_os_error() == Some(libc::EIO)
+}
+
+/// Writes `input` to the PTY, giving up after `timeout` if one is set.
+/// Returns whether all of it was written before the timeout.
+async fn write_input(
+    pty_in: &mut pty_process::OwnedWritePty,
+    input: &[u8],
+    timeout: Option<(Duration, WriteTimeoutPolicy)>,
+) -> std::io::Result<bool> {
+    let Some((timeout, policy)) = timeout else {
+        pty_in.write_all(input).await?;
+        return Ok(true);
+    };
+    match tokio::time::timeout(timeout, pty_in.write_all(input)).await {
+        Ok(written) => written.map(|()| true),
+        Err(_) => {
+            warn!(
+                bytes = input.len(),
+                ?policy,
+                "Command hasn't taken input for {timeout:?}, giving up on it"
+            );
+            Ok(false)
+        }
+    }
+}
+
+/// Adds environment variables and sets the working directory of `command`.
+pub(crate) fn configure(
+    mut command: Command,
+    env: Vec<(OsString, OsString)>,
+    cwd: Option<PathBuf>,
+) -> Command {
+    for (key, val) in env {
+        command = command.env(key, val);
+    }
+    if let Some(cwd) = cwd {
+        command = command.current_dir(cwd);
+    }
+    command
+}
+
+/// Gives up the controlling terminal pty-process assigns to the child before
+/// its pre-exec hook runs. The terminal stays the child's stdio, but no longer
+/// sends signals such as SIGINT on Ctrl-C. Doing so sends the session leader
+/// SIGHUP, which is ignored for the duration.
+fn release_controlling_terminal() -> std::io::Result<()> {
+    // SAFETY: signal() and ioctl() are async-signal-safe
+    unsafe {
+        let hangup = libc::signal(libc::SIGHUP, libc::SIG_IGN);
+        let released = libc::ioctl(0, libc::TIOCNOTTY);
+        libc::signal(libc::SIGHUP, hangup);
+        if released != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Marks every descriptor above stderr close-on-exec, so the command starts
+/// with nothing but the PTS. Closing them outright would also close the pipe
+/// std uses to report a failed exec.
+fn close_inherited_fds(open_max: libc::c_long) {
+    #[cfg(target_os = "linux")]
+    // SAFETY: close_range() is a plain syscall
+    if unsafe {
+        libc::syscall(
+            libc::SYS_close_range,
+            3,
+            u32::MAX,
+            libc::CLOSE_RANGE_CLOEXEC,
+        )
+    } == 0
+    {
+        return;
+    }
+    // fall back to going through the descriptor table one by one
+    let open_max = libc::c_int::try_from(open_max).unwrap_or(libc::c_int::MAX);
+    for fd in 3..open_max.max(3) {
+        // SAFETY: fcntl() is async-signal-safe and fails harmlessly on
+        // descriptors that aren't open
+        unsafe {
+            let flags = libc::fcntl(fd, libc::F_GETFD);
+            if flags >= 0 && flags & libc::FD_CLOEXEC == 0 {
+                libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC);
+            }
+        }
+    }
+}
+
+/// Returns the VEOF character currently configured on the PTY, falling back
+/// to Ctrl-D if the terminal attributes can't be read.
+fn eof_char(pty_fd: std::os::fd::RawFd) -> u8 {
+    const CTRL_D: u8 = 0x04;
+    match get_termios(pty_fd).map(|termios| termios.c_cc[libc::VEOF]) {
+        Ok(0) | Err(_) => CTRL_D,
+        Ok(c) => c,
+    }
+}
+
+/// Turns XON/XOFF flow control on the PTY on or off.
+fn set_flow_control(pty_fd: std::os::fd::RawFd, flow_control: FlowControl) -> std::io::Result<()> {
+    modify_termios(pty_fd, |termios| match flow_control {
+        FlowControl::None => termios.c_iflag &= !(libc::IXON | libc::IXOFF | libc::IXANY),
+        FlowControl::Software => termios.c_iflag |= libc::IXON | libc::IXOFF,
+    })
+}
+
+/// Turns off ISIG on the PTY, so the line discipline no longer swallows
+/// INTR, QUIT and SUSP characters to raise signals.
+fn disable_signal_chars(pty_fd: std::os::fd::RawFd) -> std::io::Result<()> {
+    modify_termios(pty_fd, |termios| termios.c_lflag &= !libc::ISIG)
+}
+
+/// Reads the PTY's terminal attributes.
+fn get_termios(pty_fd: std::os::fd::RawFd) -> std::io::Result<libc::termios> {
+    let mut termios = std::mem::MaybeUninit::<libc::termios>::uninit();
+    // SAFETY: `pty_fd` is an open PTY and `termios` is only read on success.
+    if unsafe { libc::tcgetattr(pty_fd, termios.as_mut_ptr()) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(unsafe { termios.assume_init() })
+}
+
+/// Changes the PTY's terminal attributes with `modify`, taking effect at once.
+fn modify_termios(
+    pty_fd: std::os::fd::RawFd,
+    modify: impl FnOnce(&mut libc::termios),
+) -> std::io::Result<()> {
+    let mut termios = get_termios(pty_fd)?;
+    modify(&mut termios);
+    // SAFETY: `termios` was filled in by tcgetattr.
+    if unsafe { libc::tcsetattr(pty_fd, libc::TCSANOW, &termios) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Sends `signal` to every process in the command's process group.
+fn signal_group(pgid: Option<libc::pid_t>, signal: libc::c_int) -> std::io::Result<()> {
+    let Some(pgid) = pgid else {
+        return Ok(());
+    };
+    // SAFETY: kill(2) has no memory-safety preconditions.
+    if unsafe { libc::kill(-pgid, signal) } == 0 {
+        Ok(())
+    } else {
+        match std::io::Error::last_os_error() {
+            // the group is already gone
+            err if err.raw_os_error() == Some(libc::ESRCH) => Ok(()),
+            err => Err(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::SinkExt;
+
+    use super::*;
+
+    fn sh(script: &str) -> Command {
+        Command::new("sh").args(["-c", script])
+    }
+
+    /// Reads output until it contains `needle`, returning all of it.
+    async fn read_until(output: &mut CommandOutputStream, needle: &str) -> String {
+        let mut read = String::new();
+        let wait = async {
+            while !read.contains(needle) {
+                match output.next().await {
+                    Some(CommandOutputItem::Output(data)) => {
+                        read.push_str(&String::from_utf8_lossy(&data));
+                    }
+                    Some(_) => {}
+                    None => panic!("output ended before {needle:?}, got {read:?}"),
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(10), wait)
+            .await
+            .unwrap_or_else(|_| panic!("timed out waiting for {needle:?}"));
+        read
+    }
+
+    /// Reads output until the command ends, returning the rest of it and
+    /// how the command ended.
+    async fn read_to_exit(output: &mut CommandOutputStream) -> (String, CommandExit) {
+        let mut read = String::new();
+        let wait = async {
+            while let Some(item) = output.next().await {
+                match item {
+                    CommandOutputItem::Output(data) => {
+                        read.push_str(&String::from_utf8_lossy(&data));
+                    }
+                    CommandOutputItem::Exit(exit) => return (read, exit),
+                    _ => {}
+                }
+            }
+            panic!("output ended without an exit, got {read:?}");
+        };
+        tokio::time::timeout(Duration::from_secs(10), wait)
+            .await
+            .expect("command should end")
+    }
+
+    #[tokio::test]
+    async fn small_input_buffer_delivers_large_input_in_order() {
+        let options = CommandOptions {
+            input_buffer: 1,
+            ..CommandOptions::default()
+        };
+        let script = "stty -echo; echo ready; \
+                      awk '$0 != NR { print \"out of order at \" NR; exit 1 } END { print \"got \" NR }'";
+        let (mut output, mut input) = start_command(sh(script), Arc::default(), options).unwrap();
+        read_until(&mut output, "ready").await;
+        let lines = (1..=5000).map(|n| format!("{n}\n")).collect::<String>();
+        for chunk in lines.as_bytes().chunks(100) {
+            input
+                .send(CommandInputItem::Input(chunk.to_vec()))
+                .await
+                .unwrap();
+        }
+        input.send(CommandInputItem::Eof).await.unwrap();
+        let (read, exit) = read_to_exit(&mut output).await;
+        assert_eq!(read, "got 5000\r\n");
+        assert_eq!(exit, CommandExit::Exited(0));
+    }
+
+    #[tokio::test]
+    async fn eof_ends_input() {
+        let (mut output, mut input) = start_command(
+            Command::new("cat"),
+            Arc::default(),
+            CommandOptions::default(),
+        )
+        .unwrap();
+        input.send(CommandInputItem::Eof).await.unwrap();
+        assert_eq!(read_to_exit(&mut output).await.1, CommandExit::Exited(0));
+    }
+
+    #[tokio::test]
+    async fn eof_uses_the_configured_character() {
+        let script = "stty eof ^B; echo ready; cat; echo done";
+        let (mut output, mut input) =
+            start_command(sh(script), Arc::default(), CommandOptions::default()).unwrap();
+        read_until(&mut output, "ready").await;
+        input.send(CommandInputItem::Eof).await.unwrap();
+        read_until(&mut output, "done").await;
+    }
+
+    /// The first two bytes the command reads after `input` is sent, in hex.
+    async fn bytes_read(flow_control: FlowControl, input: &[u8]) -> String {
+        let options = CommandOptions {
+            flow_control: Some(flow_control),
+            ..CommandOptions::default()
+        };
+        let script = "stty -icanon -echo; echo ready; head -c 2 | od -An -tx1";
+        let (mut output, mut input_sink) =
+            start_command(sh(script), Arc::default(), options).unwrap();
+        read_until(&mut output, "ready").await;
+        input_sink
+            .send(CommandInputItem::Input(input.to_vec()))
+            .await
+            .unwrap();
+        read_to_exit(&mut output).await.0.trim().to_string()
+    }
+
+    #[tokio::test]
+    async fn flow_control_none_passes_ctrl_s_and_ctrl_q() {
+        assert_eq!(bytes_read(FlowControl::None, b"\x13\x11").await, "13 11");
+    }
+
+    #[tokio::test]
+    async fn software_flow_control_takes_ctrl_s_and_ctrl_q() {
+        assert_eq!(
+            bytes_read(FlowControl::Software, b"\x13a\x11b").await,
+            "61 62"
+        );
+    }
+
+    /// The descriptors the command has open, with a descriptor leaked into
+    /// it unless `close_fds` is set.
+    #[cfg(target_os = "linux")]
+    async fn open_fds(close_fds: bool) -> (Vec<String>, String) {
+        // SAFETY: dup() returns a new descriptor without close-on-exec
+        let leaked = unsafe { libc::dup(2) };
+        assert!(leaked >= 0);
+        let options = CommandOptions {
+            close_fds,
+            ..CommandOptions::default()
+        };
+        let spawned = start_command(sh("ls /proc/$$/fd"), Arc::default(), options);
+        // SAFETY: `leaked` was opened above and isn't used elsewhere
+        unsafe { libc::close(leaked) };
+        let (mut output, _input) = spawned.unwrap();
+        let (read, _) = read_to_exit(&mut output).await;
+        let fds = read.split_whitespace().map(str::to_string).collect();
+        (fds, leaked.to_string())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn command_gets_only_the_terminal() {
+        let (fds, _) = open_fds(true).await;
+        assert_eq!(fds, ["0", "1", "2"]);
+        let (fds, leaked) = open_fds(false).await;
+        assert!(fds.contains(&leaked), "{fds:?}");
+    }
+
+    /// Resizes a command that waits for SIGWINCH, checking it arrives.
+    async fn resize_signals_command(controlling_terminal: bool) {
+        let options = CommandOptions {
+            controlling_terminal,
+            ..CommandOptions::default()
+        };
+        let script = "trap 'stty size; exit 0' WINCH; echo ready; while :; do sleep 0.1; done";
+        let (mut output, mut input) = start_command(sh(script), Arc::default(), options).unwrap();
+        read_until(&mut output, "ready").await;
+        let size = Size::new(30, 100);
+        input.send(CommandInputItem::Resize(size)).await.unwrap();
+        let (read, exit) = read_to_exit(&mut output).await;
+        assert!(read.contains("30 100"), "{read:?}");
+        assert_eq!(exit, CommandExit::Exited(0));
+    }
+
+    #[tokio::test]
+    async fn resize_signals_command_with_controlling_terminal() {
+        resize_signals_command(true).await;
+    }
+
+    #[tokio::test]
+    async fn resize_signals_command_without_controlling_terminal() {
+        resize_signals_command(false).await;
+    }
+
+    /// Starts a command that never reads and floods it with input.
+    async fn flood_idle_command(
+        policy: WriteTimeoutPolicy,
+    ) -> (CommandOutputStream, CommandInputSink) {
+        let options = CommandOptions {
+            write_timeout: Some((Duration::from_millis(200), policy)),
+            kill_grace: Duration::from_millis(100),
+            ..CommandOptions::default()
+        };
+        let script = "stty -icanon -echo; echo ready; sleep 100";
+        let (mut output, mut input) = start_command(sh(script), Arc::default(), options).unwrap();
+        read_until(&mut output, "ready").await;
+        let flood = CommandInputItem::Input(vec![b'x'; 1 << 20]);
+        input.send(flood).await.unwrap();
+        (output, input)
+    }
+
+    #[tokio::test]
+    async fn write_timeout_drops_input_the_command_wont_take() {
+        let (mut output, mut input) = flood_idle_command(WriteTimeoutPolicy::Drop).await;
+        let size = Size::new(30, 100);
+        input.send(CommandInputItem::Resize(size)).await.unwrap();
+        let resized = async {
+            while let Some(item) = output.next().await {
+                if let CommandOutputItem::Resized(resized) = item {
+                    return resized;
+                }
+            }
+            panic!("output ended before the resize");
+        };
+        let resized = tokio::time::timeout(Duration::from_secs(5), resized).await;
+        assert_eq!(resized.expect("input should move on"), size);
+    }
+
+    #[tokio::test]
+    async fn write_timeout_aborts_command_that_wont_take_input() {
+        let (mut output, _input) = flood_idle_command(WriteTimeoutPolicy::Abort).await;
+        assert_eq!(read_to_exit(&mut output).await.1, CommandExit::Aborted);
+    }
+
+    #[tokio::test]
+    async fn forwards_multibyte_text_intact() {
+        let script = "read -r line; printf '%s' \"$line\" | od -An -tx1";
+        let (mut output, mut input) =
+            start_command(sh(script), Arc::default(), CommandOptions::default()).unwrap();
+        for text in ["\u{e9}", "\u{1f600}", "\r"] {
+            let item = CommandInputItem::InputString(text.to_string());
+            input.send(item).await.unwrap();
+        }
+        read_until(&mut output, "c3 a9 f0 9f 98 80").await;
+    }
+
+    #[tokio::test]
+    async fn command_without_controlling_terminal_gets_ctrl_c_as_input() {
+        let options = CommandOptions {
+            controlling_terminal: false,
+            ..CommandOptions::default()
+        };
+        let script = "[ -t 0 ] && [ -t 1 ] && echo tty; \
+                      stty -icanon -echo; echo ready; head -c 1 | od -An -tx1";
+        let (mut output, mut input) = start_command(sh(script), Arc::default(), options).unwrap();
+        read_until(&mut output, "tty").await;
+        read_until(&mut output, "ready").await;
+        input
+            .send(CommandInputItem::Input(vec![0x03]))
+            .await
+            .unwrap();
+        read_until(&mut output, "03").await;
+        assert_eq!(read_to_exit(&mut output).await.1, CommandExit::Exited(0));
+    }
+
+    #[cfg(target_os = "linux")]
+    /// Whether the process `pid` has died, waiting a little for it to. A
+    /// zombie counts as dead, as reaping it is up to whoever adopted it.
+    async fn process_gone(pid: u32) -> bool {
+        for _ in 0..50 {
+            let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).unwrap_or_default();
+            let state = stat
+                .rsplit_once(") ")
+                .and_then(|(_, rest)| rest.chars().next());
+            if matches!(state, None | Some('Z' | 'X')) {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        false
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn abort_kills_background_processes() {
+        let aborter = Arc::new(Notify::new());
+        let script = "sleep 1003 & echo \"bg $! \"; wait";
+        let (mut output, _input) =
+            start_command(sh(script), aborter.clone(), CommandOptions::default()).unwrap();
+        let read = read_until(&mut output, " \r\n").await;
+        let pid = read.split_whitespace().nth(1).unwrap().parse().unwrap();
+        aborter.notify_one();
+        assert_eq!(read_to_exit(&mut output).await.1, CommandExit::Aborted);
+        assert!(process_gone(pid).await);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn abort_kills_background_processes_ignoring_sigterm() {
+        let aborter = Arc::new(Notify::new());
+        // the shell exits on SIGTERM, leaving behind a sleep that ignores it and
+        // the SIGHUP sent when the shell's session ends
+        let script = "sh -c 'trap \"\" TERM HUP; echo \"bg $$ \"; exec sleep 1004' & \
+                      trap 'exit 0' TERM; wait";
+        let options = CommandOptions {
+            kill_grace: Duration::from_secs(10),
+            ..CommandOptions::default()
+        };
+        let (mut output, _input) = start_command(sh(script), aborter.clone(), options).unwrap();
+        let read = read_until(&mut output, " \r\n").await;
+        let pid = read.split_whitespace().nth(1).unwrap().parse().unwrap();
+        aborter.notify_one();
+        assert_eq!(read_to_exit(&mut output).await.1, CommandExit::Aborted);
+        assert!(process_gone(pid).await);
+    }
+
+    #[tokio::test]
+    async fn aborted_command_may_exit_within_grace_period() {
+        let aborter = Arc::new(Notify::new());
+        let options = CommandOptions {
+            kill_grace: Duration::from_secs(5),
+            ..CommandOptions::default()
+        };
+        let script = "trap 'exit 0' TERM; echo ready; while :; do sleep 0.1; done";
+        let (mut output, _input) = start_command(sh(script), aborter.clone(), options).unwrap();
+        read_until(&mut output, "ready").await;
+        let started = Instant::now();
+        aborter.notify_one();
+        assert_eq!(read_to_exit(&mut output).await.1, CommandExit::Aborted);
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn command_ignoring_sigterm_is_killed_after_grace_period() {
+        let aborter = Arc::new(Notify::new());
+        let options = CommandOptions {
+            kill_grace: Duration::from_millis(500),
+            ..CommandOptions::default()
+        };
+        let script = "trap '' TERM; echo ready; while :; do sleep 0.1; done";
+        let (mut output, _input) = start_command(sh(script), aborter.clone(), options).unwrap();
+        read_until(&mut output, "ready").await;
+        let started = Instant::now();
+        aborter.notify_one();
+        assert_eq!(read_to_exit(&mut output).await.1, CommandExit::Aborted);
+        assert!(started.elapsed() >= Duration::from_millis(500));
+    }
+
+    #[test]
+    fn eio_is_a_closed_pty() {
+        assert!(is_pty_closed(&std::io::Error::from_raw_os_error(libc::EIO)));
+    }
+
+    #[test]
+    fn other_errors_are_not() {
+        assert!(!is_pty_closed(&std::io::Error::from_raw_os_error(
+            libc::EBADF
+        )));
+        assert!(!is_pty_closed(&std::io::Error::other("EIO")));
+    }
+}
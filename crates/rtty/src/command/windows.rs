@@ -0,0 +1,230 @@
+use std::{
+    ffi::OsString,
+    io::{Read, Write},
+    path::PathBuf,
+    sync::Arc,
+};
+
+use async_stream::stream;
+use bytes::Bytes;
+use portable_pty::{PtySize, native_pty_system};
+use tokio::sync::Notify;
+use tracing::{debug, error};
+
+use crate::{
+    CommandExit, CommandInputItem, CommandInputSink, CommandOptions, CommandOutputItem,
+    CommandOutputStream, Error, OutputScanner, Size, Spawned, bounded_output,
+};
+
+pub type Command = portable_pty::CommandBuilder;
+
+/// Written for [`CommandInputItem::Eof`]; the console's end-of-file key.
+const CTRL_Z: u8 = 0x1a;
+
+impl From<Size> for PtySize {
+    fn from(size: Size) -> Self {
+        Self {
+            rows: size.rows,
+            cols: size.cols,
+            pixel_width: 0,
+            pixel_height: 0,
+        }
+    }
+}
+
+/// Spawns `command` on a new ConPTY.
+///
+/// ConPTY only offers blocking I/O, so reads, writes and waiting for the
+/// child each run on a dedicated thread. When `aborter` is notified the
+/// command is killed immediately; `options.kill_grace` does not apply.
+pub fn start_command(
+    command: Command,
+    aborter: Arc<Notify>,
+    options: CommandOptions,
+) -> Result<(CommandOutputStream, CommandInputSink), Error> {
+    spawn(command, aborter, options).map(|spawned| (spawned.output, spawned.input))
+}
+
+/// Like [`start_command`], also returning the child's process id.
+pub(crate) fn spawn(
+    command: Command,
+    aborter: Arc<Notify>,
+    options: CommandOptions,
+) -> Result<Spawned, Error> {
+    let CommandOptions {
+        size,
+        input_buffer,
+        detect_title,
+        detect_bell,
+        output_limit,
+        ..
+    } = options;
+    let pair = native_pty_system()
+        .openpty(size.unwrap_or(Size::new(24, 80)).clamped().into())
+        .map_err(std::io::Error::other)?;
+    let mut child = pair
+        .slave
+        .spawn_command(command)
+        .map_err(std::io::Error::other)?;
+    drop(pair.slave);
+    let pid = child.process_id();
+    let mut killer = child.clone_killer();
+    let mut reader = pair
+        .master
+        .try_clone_reader()
+        .map_err(std::io::Error::other)?;
+    let mut writer = pair.master.take_writer().map_err(std::io::Error::other)?;
+    let master = pair.master;
+
+    let (output_tx, mut output_rx) = tokio::sync::mpsc::channel::<std::io::Result<Bytes>>(16);
+    std::thread::spawn(move || {
+        let mut buf = vec![0; 8192];
+        loop {
+            let output = match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => Ok(Bytes::copy_from_slice(&buf[..n])),
+                Err(err) => Err(err),
+            };
+            let failed = output.is_err();
+            if output_tx.blocking_send(output).is_err() || failed {
+                break;
+            }
+        }
+    });
+
+    let (exit_tx, mut exit_rx) = tokio::sync::oneshot::channel();
+    std::thread::spawn(move || exit_tx.send(child.wait()));
+
+    let mut scanner =
+        (detect_title || detect_bell).then(|| OutputScanner::new(detect_title, detect_bell));
+
+    let (resized_tx, mut resized_rx) = tokio::sync::mpsc::unbounded_channel();
+
+    let stream = futures_util::StreamExt::boxed(stream! {
+        let mut aborted = false;
+        loop {
+            tokio::select! {
+                Some(output) = output_rx.recv() =>
+                    match output {
+                        Ok(b) => {
+                            let events = scanner.as_mut().map(|s| s.feed(&b)).unwrap_or_default();
+                            yield CommandOutputItem::Output(b);
+                            for event in events {
+                                yield event;
+                            }
+                        }
+                        Err(err) => yield CommandOutputItem::Error(err.to_string()),
+                    },
+                Some(size) = resized_rx.recv() => yield CommandOutputItem::Resized(size),
+                status = &mut exit_rx => {
+                    match status {
+                        Ok(Err(err)) => yield CommandOutputItem::Error(err.to_string()),
+                        Ok(Ok(_)) | Err(_) if aborted => {
+                            yield CommandOutputItem::Exit(CommandExit::Aborted);
+                        }
+                        Ok(Ok(status)) => {
+                            // Windows exit codes are unsigned; keep their bit pattern
+                            let code = status.exit_code().cast_signed();
+                            yield CommandOutputItem::Exit(CommandExit::Exited(code));
+                        }
+                        Err(_) => yield CommandOutputItem::Error("Lost track of command".to_string()),
+                    }
+                    break;
+                },
+                _ = aborter.notified(), if !aborted => {
+                    aborted = true;
+                    match killer.kill() {
+                        Ok(()) => debug!("Command aborted"),
+                        Err(err) => error!("Failed to abort command: {err}"),
+                    };
+                }
+            }
+        }
+    });
+
+    let (input_tx, mut input_rx) = tokio::sync::mpsc::channel::<CommandInputItem>(input_buffer);
+    let input_sink = Box::pin(tokio_util::sync::PollSender::new(input_tx));
+
+    // ends once the input sink is dropped
+    std::thread::spawn(move || {
+        while let Some(input) = input_rx.blocking_recv() {
+            let written = match input {
+                CommandInputItem::Input(input) => writer.write_all(&input),
+                CommandInputItem::InputString(input) => writer.write_all(input.as_bytes()),
+                CommandInputItem::Resize(size) => {
+                    let size = size.clamped();
+                    if master.resize(size.into()).is_ok() {
+                        resized_tx.send(size).ok();
+                    }
+                    Ok(())
+                }
+                CommandInputItem::Eof => writer.write_all(&[CTRL_Z]),
+            };
+            if let Err(err) = written.and_then(|()| writer.flush()) {
+                error!("Failed to write to command: {err}");
+                break;
+            }
+        }
+    });
+
+    let stream = match output_limit {
+        Some((limit, policy)) => bounded_output(stream, limit, policy),
+        None => stream,
+    };
+
+    Ok(Spawned {
+        output: stream,
+        input: input_sink,
+        pid,
+    })
+}
+
+/// Adds environment variables and sets the working directory of `command`.
+pub(crate) fn configure(
+    mut command: Command,
+    env: Vec<(OsString, OsString)>,
+    cwd: Option<PathBuf>,
+) -> Command {
+    for (key, val) in env {
+        command.env(key, val);
+    }
+    if let Some(cwd) = cwd {
+        command.cwd(cwd);
+    }
+    command
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures_util::StreamExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn runs_command_and_reports_exit_code() {
+        let mut command = Command::new("cmd");
+        command.args(["/c", "echo hello& exit 3"]);
+        let (mut output, _input) =
+            start_command(command, Arc::default(), CommandOptions::default()).unwrap();
+        let mut read = String::new();
+        let exit = async {
+            while let Some(item) = output.next().await {
+                match item {
+                    CommandOutputItem::Output(data) => {
+                        read.push_str(&String::from_utf8_lossy(&data));
+                    }
+                    CommandOutputItem::Exit(exit) => return exit,
+                    _ => {}
+                }
+            }
+            panic!("output ended without an exit, got {read:?}");
+        };
+        let exit = tokio::time::timeout(Duration::from_secs(30), exit)
+            .await
+            .expect("command should end");
+        assert_eq!(exit, CommandExit::Exited(3));
+        assert!(read.contains("hello"), "{read:?}");
+    }
+}
@@ -0,0 +1,8 @@
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[cfg(unix)]
+    #[error(transparent)]
+    Pty(#[from] pty_process::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
@@ -0,0 +1,166 @@
+use async_stream::stream;
+use bytes::Bytes;
+use futures_util::StreamExt;
+
+use crate::{CommandOutputItem, CommandOutputStream};
+
+const BEL: u8 = 0x07;
+const ESC: u8 = 0x1b;
+
+/// Rewrites a command's output before it is passed on, e.g. to strip
+/// escape sequences or redact secrets.
+///
+/// Chunks are cut wherever the PTY read happened to end, so escape sequences
+/// and multibyte characters may be split across calls. Filters that need to
+/// see them whole keep the incomplete part back and emit it with a later
+/// chunk.
+pub trait OutputFilter: Send {
+    /// Returns what to pass on in place of `chunk`. Empty output is not
+    /// passed on at all.
+    fn transform(&mut self, chunk: Bytes) -> Bytes;
+
+    /// Returns anything still held back once the command has exited.
+    fn finish(&mut self) -> Bytes {
+        Bytes::new()
+    }
+}
+
+/// Runs every [`CommandOutputItem::Output`] of `output` through `filter`,
+/// flushing it before the exit is reported.
+pub(crate) fn filter_output(
+    mut output: CommandOutputStream,
+    mut filter: Box<dyn OutputFilter>,
+) -> CommandOutputStream {
+    stream! {
+        while let Some(item) = output.next().await {
+            match item {
+                CommandOutputItem::Output(chunk) => {
+                    let chunk = filter.transform(chunk);
+                    if !chunk.is_empty() {
+                        yield CommandOutputItem::Output(chunk);
+                    }
+                }
+                CommandOutputItem::Exit(exit) => {
+                    let rest = filter.finish();
+                    if !rest.is_empty() {
+                        yield CommandOutputItem::Output(rest);
+                    }
+                    yield CommandOutputItem::Exit(exit);
+                }
+                item => yield item,
+            }
+        }
+    }
+    .boxed()
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum State {
+    #[default]
+    Ground,
+    Escape,
+    /// After `ESC` and intermediate bytes such as the `(` of `ESC ( B`.
+    Intermediate,
+    Csi,
+    /// OSC, DCS, SOS, PM and APC strings, ended by BEL or `ESC \`.
+    String,
+    StringEscape,
+}
+
+/// Removes escape sequences from output, leaving plain text with its line
+/// breaks and other control characters.
+///
+/// State is carried between calls, so sequences split across chunks are
+/// still removed. Only 7-bit sequences are recognised; bytes that would be
+/// 8-bit C1 controls are left alone, as they are also part of UTF-8
+/// characters.
+#[derive(Debug, Default)]
+pub struct AnsiStripper {
+    state: State,
+}
+
+impl AnsiStripper {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl OutputFilter for AnsiStripper {
+    fn transform(&mut self, chunk: Bytes) -> Bytes {
+        let mut text = Vec::with_capacity(chunk.len());
+        for &b in &chunk {
+            self.state = match (self.state, b) {
+                (State::Ground, ESC) => State::Escape,
+                (State::Ground, _) => {
+                    text.push(b);
+                    State::Ground
+                }
+                (State::Escape, b'[') => State::Csi,
+                (State::Escape, b']' | b'P' | b'X' | b'^' | b'_') => State::String,
+                (State::Escape | State::Intermediate, 0x20..=0x2f) => State::Intermediate,
+                // a final byte, or a stray ESC followed by a control character
+                (State::Escape | State::Intermediate, _) => State::Ground,
+                (State::Csi, 0x40..=0x7e) => State::Ground,
+                (State::Csi, _) => State::Csi,
+                (State::String | State::StringEscape, BEL) | (State::StringEscape, b'\\') => {
+                    State::Ground
+                }
+                (State::String | State::StringEscape, ESC) => State::StringEscape,
+                (State::String | State::StringEscape, _) => State::String,
+            };
+        }
+        text.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Strips `chunks` one after another, returning all the text kept.
+    fn strip(chunks: &[&[u8]]) -> Vec<u8> {
+        let mut stripper = AnsiStripper::new();
+        let mut text = Vec::new();
+        for chunk in chunks {
+            text.extend_from_slice(&stripper.transform(Bytes::copy_from_slice(chunk)));
+        }
+        text.extend_from_slice(&stripper.finish());
+        text
+    }
+
+    #[test]
+    fn strips_csi() {
+        assert_eq!(strip(&[b"\x1b[1;31mred\x1b[0m\r\n"]), b"red\r\n");
+        assert_eq!(strip(&[b"a\x1b[?25lb\x1b[2Jc"]), b"abc");
+    }
+
+    #[test]
+    fn strips_osc_ended_by_bel_or_st() {
+        assert_eq!(strip(&[b"a\x1b]0;title\x07b"]), b"ab");
+        assert_eq!(
+            strip(&[b"a\x1b]8;;http://x\x1b\\link\x1b]8;;\x1b\\b"]),
+            b"alinkb"
+        );
+    }
+
+    #[test]
+    fn strips_escapes_with_intermediates() {
+        assert_eq!(strip(&[b"a\x1b(Bb\x1b)0c"]), b"abc");
+        assert_eq!(strip(&[b"a\x1b=b\x1b>c"]), b"abc");
+    }
+
+    #[test]
+    fn strips_sequences_split_across_chunks() {
+        assert_eq!(strip(&[b"a\x1b", b"[3", b"1m", b"b"]), b"ab");
+        assert_eq!(strip(&[b"a\x1b]0;ti", b"tle\x1b", b"\\b"]), b"ab");
+        assert_eq!(strip(&[b"a\x1b(", b"Bb"]), b"ab");
+    }
+
+    #[test]
+    fn keeps_text_and_utf8() {
+        assert_eq!(
+            strip(&["héllo\tw\u{f6}rld\r\n".as_bytes()]),
+            "héllo\tw\u{f6}rld\r\n".as_bytes()
+        );
+    }
+}
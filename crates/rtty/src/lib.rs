@@ -1,3 +1,13 @@
 mod command;
+mod error;
+mod filter;
+mod overflow;
+mod scanner;
+mod session;
 
 pub use command::*;
+pub use error::Error;
+pub use filter::{AnsiStripper, OutputFilter};
+pub use overflow::{OverflowPolicy, TRUNCATED_MARKER, bounded_output};
+pub use scanner::OutputScanner;
+pub use session::{Session, SessionBuilder, SessionExit, SessionHandle};
@@ -0,0 +1,233 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use async_stream::stream;
+use bytes::Bytes;
+use tokio::sync::Notify;
+use tokio_stream::StreamExt;
+use tracing::Instrument;
+
+use crate::{CommandOutputItem, CommandOutputStream};
+
+/// Written in place of output dropped by [`OverflowPolicy::Drop`].
+pub const TRUNCATED_MARKER: &[u8] = b"\r\n[output truncated]\r\n";
+
+/// What to do when a consumer falls behind and buffered output reaches its
+/// limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Discard the oldest buffered output and mark the gap with
+    /// [`TRUNCATED_MARKER`]; the command keeps running at full speed.
+    Drop,
+    /// Stop reading from the PTY until the consumer catches up, which
+    /// eventually blocks the command's writes.
+    Block,
+}
+
+#[derive(Default)]
+struct Buffer {
+    items: VecDeque<CommandOutputItem>,
+    bytes: usize,
+    truncated: bool,
+    finished: bool,
+    closed: bool,
+}
+
+#[derive(Default)]
+struct Shared {
+    buffer: Mutex<Buffer>,
+    readable: Notify,
+    writable: Notify,
+}
+
+/// Marks the buffer closed once the consumer goes away, so a blocked pump
+/// keeps draining the command instead of waiting forever.
+struct CloseGuard(Arc<Shared>);
+
+impl Drop for CloseGuard {
+    fn drop(&mut self) {
+        self.0.buffer.lock().unwrap().closed = true;
+        self.0.writable.notify_one();
+    }
+}
+
+/// Decouples reading `stream` from consuming it, holding at most `limit`
+/// bytes of unsent output in between.
+///
+/// A background task keeps pulling from `stream` so the command is never
+/// stalled by a slow consumer unless `policy` is [`OverflowPolicy::Block`].
+pub fn bounded_output(
+    mut stream: CommandOutputStream,
+    limit: usize,
+    policy: OverflowPolicy,
+) -> CommandOutputStream {
+    let shared = Arc::new(Shared::default());
+
+    let pump = shared.clone();
+    tokio::spawn(
+        async move {
+            while let Some(item) = stream.next().await {
+                let len = match &item {
+                    CommandOutputItem::Output(b) => b.len(),
+                    _ => 0,
+                };
+                let mut item = Some(item);
+                while let Some(pending) = item.take() {
+                    let waiting = {
+                        let mut buffer = pump.buffer.lock().unwrap();
+                        let full = buffer.bytes > 0 && buffer.bytes + len > limit;
+                        if buffer.closed {
+                            false
+                        } else if full && policy == OverflowPolicy::Block {
+                            item = Some(pending);
+                            true
+                        } else {
+                            buffer.bytes += len;
+                            buffer.items.push_back(pending);
+                            if buffer.bytes > limit {
+                                truncate(&mut buffer, limit);
+                            }
+                            false
+                        }
+                    };
+                    if waiting {
+                        pump.writable.notified().await;
+                    } else {
+                        pump.readable.notify_one();
+                    }
+                }
+            }
+            pump.buffer.lock().unwrap().finished = true;
+            pump.readable.notify_one();
+        }
+        .in_current_span(),
+    );
+
+    futures_util::StreamExt::boxed(stream! {
+        let guard = CloseGuard(shared);
+        loop {
+            let next = {
+                let mut buffer = guard.0.buffer.lock().unwrap();
+                if std::mem::take(&mut buffer.truncated) {
+                    Some(CommandOutputItem::Output(Bytes::from_static(TRUNCATED_MARKER)))
+                } else if let Some(item) = buffer.items.pop_front() {
+                    if let CommandOutputItem::Output(b) = &item {
+                        buffer.bytes -= b.len();
+                    }
+                    guard.0.writable.notify_one();
+                    Some(item)
+                } else if buffer.finished {
+                    break;
+                } else {
+                    None
+                }
+            };
+            match next {
+                Some(item) => yield item,
+                None => guard.0.readable.notified().await,
+            }
+        }
+    })
+}
+
+/// Drops the oldest output until at most `limit` bytes remain buffered.
+fn truncate(buffer: &mut Buffer, limit: usize) {
+    let mut index = 0;
+    while buffer.bytes > limit && index < buffer.items.len() {
+        let excess = buffer.bytes - limit;
+        match &mut buffer.items[index] {
+            CommandOutputItem::Output(b) if b.len() <= excess => {
+                buffer.bytes -= b.len();
+                buffer.items.remove(index);
+            }
+            CommandOutputItem::Output(b) => {
+                *b = b.slice(excess..);
+                buffer.bytes -= excess;
+            }
+            _ => index += 1,
+        }
+        buffer.truncated = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use super::*;
+    use crate::CommandExit;
+
+    /// A command printing `chunks` and exiting, counting how many items have
+    /// been read from it in `read` and notifying `done` once all have.
+    fn command(
+        chunks: &'static [&'static str],
+        read: Arc<AtomicUsize>,
+        done: Arc<Notify>,
+    ) -> CommandOutputStream {
+        futures_util::StreamExt::boxed(stream! {
+            for chunk in chunks {
+                read.fetch_add(1, Ordering::SeqCst);
+                yield CommandOutputItem::Output(Bytes::from_static(chunk.as_bytes()));
+            }
+            read.fetch_add(1, Ordering::SeqCst);
+            yield CommandOutputItem::Exit(CommandExit::Exited(0));
+            done.notify_one();
+        })
+    }
+
+    async fn collect(mut output: CommandOutputStream) -> Vec<String> {
+        let mut items = Vec::new();
+        while let Some(item) = output.next().await {
+            items.push(match item {
+                CommandOutputItem::Output(b) => String::from_utf8(b.to_vec()).unwrap(),
+                CommandOutputItem::Exit(exit) => exit.to_string(),
+                item => panic!("unexpected {item:?}"),
+            });
+        }
+        items
+    }
+
+    #[tokio::test]
+    async fn drop_keeps_the_newest_output_and_marks_the_gap() {
+        let done = Arc::new(Notify::new());
+        let chunks = &["aaaa", "bbbb", "cccc", "dddd", "eeee"];
+        let source = command(chunks, Arc::default(), done.clone());
+        let output = bounded_output(source, 10, OverflowPolicy::Drop);
+        // let the command finish before anything is consumed
+        done.notified().await;
+        let marker = std::str::from_utf8(TRUNCATED_MARKER).unwrap();
+        let exit = CommandExit::Exited(0).to_string();
+        assert_eq!(
+            collect(output).await,
+            [marker, "cc", "dddd", "eeee", exit.as_str()]
+        );
+    }
+
+    #[tokio::test]
+    async fn drop_passes_everything_on_within_the_limit() {
+        let done = Arc::new(Notify::new());
+        let source = command(&["aaaa", "bbbb"], Arc::default(), done.clone());
+        let output = bounded_output(source, 8, OverflowPolicy::Drop);
+        done.notified().await;
+        let exit = CommandExit::Exited(0).to_string();
+        assert_eq!(collect(output).await, ["aaaa", "bbbb", exit.as_str()]);
+    }
+
+    #[tokio::test]
+    async fn block_stops_reading_until_output_is_consumed() {
+        let read = Arc::new(AtomicUsize::new(0));
+        let source = command(&["aaaa", "bbbb", "cccc"], read.clone(), Arc::default());
+        let output = bounded_output(source, 4, OverflowPolicy::Block);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // "aaaa" fills the buffer, so "bbbb" waits and "cccc" isn't read
+        assert_eq!(read.load(Ordering::SeqCst), 2);
+        let exit = CommandExit::Exited(0).to_string();
+        assert_eq!(
+            collect(output).await,
+            ["aaaa", "bbbb", "cccc", exit.as_str()]
+        );
+    }
+}
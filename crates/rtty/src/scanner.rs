@@ -0,0 +1,180 @@
+use crate::CommandOutputItem;
+
+/// Longest title kept; longer OSC payloads are dropped.
+const MAX_TITLE_LEN: usize = 4096;
+
+/// Longest OSC number looked at; sequences with longer ones are ignored.
+const MAX_PARAM_LEN: usize = 3;
+
+const BEL: u8 = 0x07;
+const ESC: u8 = 0x1b;
+
+#[derive(Debug, Default)]
+enum State {
+    #[default]
+    Ground,
+    Escape,
+    Param(Vec<u8>),
+    Title(Vec<u8>),
+    TitleEscape(Vec<u8>),
+    Ignore,
+    IgnoreEscape,
+}
+
+/// Picks terminal events out of PTY output: window titles set via
+/// `OSC 0`/`OSC 2` (`ESC ] 0 ; title BEL` or `ESC ] 2 ; title ESC \\`) and
+/// bells rung with a BEL outside of such a sequence.
+///
+/// State is carried between calls, so sequences split across chunks are
+/// still recognised.
+#[derive(Debug)]
+pub struct OutputScanner {
+    state: State,
+    titles: bool,
+    bell: bool,
+}
+
+impl OutputScanner {
+    /// Creates a scanner reporting title changes, bells, or both.
+    pub fn new(titles: bool, bell: bool) -> Self {
+        Self {
+            state: State::Ground,
+            titles,
+            bell,
+        }
+    }
+
+    /// Scans `chunk` and returns the [`CommandOutputItem::Title`] and
+    /// [`CommandOutputItem::Bell`] events completed within it.
+    pub fn feed(&mut self, chunk: &[u8]) -> Vec<CommandOutputItem> {
+        let mut events = Vec::new();
+        for &b in chunk {
+            self.state = match (std::mem::take(&mut self.state), b) {
+                (State::Ground, BEL) => {
+                    if self.bell {
+                        events.push(CommandOutputItem::Bell);
+                    }
+                    State::Ground
+                }
+                (State::Escape, b']') => State::Param(Vec::new()),
+                (State::Param(mut param), b'0'..=b'9') if param.len() < MAX_PARAM_LEN => {
+                    param.push(b);
+                    State::Param(param)
+                }
+                (State::Param(_), b'0'..=b'9') => State::Ignore,
+                (State::Param(param), b';') if self.titles && (param == b"0" || param == b"2") => {
+                    State::Title(Vec::new())
+                }
+                (State::Param(_), b';') => State::Ignore,
+                (State::Title(title) | State::TitleEscape(title), BEL)
+                | (State::TitleEscape(title), b'\\') => {
+                    let title = String::from_utf8_lossy(&title).into_owned();
+                    events.push(CommandOutputItem::Title(title));
+                    State::Ground
+                }
+                (State::Title(title), ESC) => State::TitleEscape(title),
+                (State::Title(mut title), _) if title.len() < MAX_TITLE_LEN => {
+                    title.push(b);
+                    State::Title(title)
+                }
+                (State::Title(_), _) => State::Ignore,
+                (State::Ignore | State::IgnoreEscape, BEL) | (State::IgnoreEscape, b'\\') => {
+                    State::Ground
+                }
+                (State::Ignore, ESC) => State::IgnoreEscape,
+                (State::Ignore, _) => State::Ignore,
+                // any other escape aborts the sequence and starts a new one
+                (_, ESC) => State::Escape,
+                _ => State::Ground,
+            };
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds `chunks` one after another, describing the events found.
+    fn scan(scanner: &mut OutputScanner, chunks: &[&[u8]]) -> Vec<String> {
+        chunks
+            .iter()
+            .flat_map(|chunk| scanner.feed(chunk))
+            .map(|event| match event {
+                CommandOutputItem::Title(title) => format!("title {title}"),
+                CommandOutputItem::Bell => "bell".to_string(),
+                event => panic!("unexpected {event:?}"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn title_ended_by_bel() {
+        let mut scanner = OutputScanner::new(true, true);
+        assert_eq!(
+            scan(&mut scanner, &[b"a\x1b]0;hello\x07b"]),
+            ["title hello"]
+        );
+    }
+
+    #[test]
+    fn title_ended_by_st() {
+        let mut scanner = OutputScanner::new(true, true);
+        assert_eq!(
+            scan(&mut scanner, &[b"\x1b]2;hello\x1b\\"]),
+            ["title hello"]
+        );
+    }
+
+    #[test]
+    fn title_split_across_chunks() {
+        let mut scanner = OutputScanner::new(true, true);
+        let chunks: &[&[u8]] = &[b"\x1b", b"]", b"0", b";hel", b"lo", b"\x07"];
+        assert_eq!(scan(&mut scanner, chunks), ["title hello"]);
+        let chunks: &[&[u8]] = &[b"\x1b]2;wor", b"ld\x1b", b"\\"];
+        assert_eq!(scan(&mut scanner, chunks), ["title world"]);
+    }
+
+    #[test]
+    fn bell_outside_sequences() {
+        let mut scanner = OutputScanner::new(true, true);
+        assert_eq!(scan(&mut scanner, &[b"\x07", b"a\x07"]), ["bell", "bell"]);
+        // the BEL ending an OSC isn't a bell
+        assert_eq!(
+            scan(&mut scanner, &[b"\x1b]8;;url\x07"]),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            scan(&mut scanner, &[b"\x1b]0;t\x07\x07"]),
+            ["title t", "bell"]
+        );
+    }
+
+    #[test]
+    fn reports_only_what_was_asked_for() {
+        let mut scanner = OutputScanner::new(false, true);
+        assert_eq!(scan(&mut scanner, &[b"\x1b]0;hi\x07\x07"]), ["bell"]);
+        let mut scanner = OutputScanner::new(true, false);
+        assert_eq!(scan(&mut scanner, &[b"\x1b]0;hi\x07\x07"]), ["title hi"]);
+    }
+
+    #[test]
+    fn drops_overlong_titles() {
+        let mut scanner = OutputScanner::new(true, true);
+        let title = vec![b'x'; MAX_TITLE_LEN + 1];
+        let events = scan(&mut scanner, &[b"\x1b]0;", &title, b"\x07"]);
+        assert!(events.is_empty());
+        assert_eq!(scan(&mut scanner, &[b"\x1b]0;ok\x07"]), ["title ok"]);
+    }
+
+    #[test]
+    fn ignores_overlong_params() {
+        let mut scanner = OutputScanner::new(true, true);
+        let param = vec![b'0'; 10_000];
+        let events = scan(&mut scanner, &[b"\x1b]", &param, b";title\x07"]);
+        assert!(events.is_empty());
+        assert!(matches!(scanner.state, State::Ground));
+        assert_eq!(scan(&mut scanner, &[b"\x1b]0;ok\x07"]), ["title ok"]);
+    }
+}
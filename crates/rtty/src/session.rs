@@ -0,0 +1,267 @@
+use std::{
+    ffi::{OsStr, OsString},
+    future::Future,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    time::Duration,
+};
+
+use futures_util::StreamExt;
+use tokio::sync::{Notify, oneshot};
+
+use crate::{
+    Command, CommandExit, CommandInputSink, CommandOptions, CommandOutputItem, CommandOutputStream,
+    Error, FlowControl, OutputFilter, OverflowPolicy, Size, WriteTimeoutPolicy, configure,
+    filter::filter_output, spawn,
+};
+
+/// Resolves to how the command ended, or `None` if the output stream was
+/// dropped before reporting it.
+pub type SessionExit = Pin<Box<dyn Future<Output = Option<CommandExit>> + Send>>;
+
+/// Configures and starts a command on a new PTY.
+pub struct SessionBuilder {
+    command: Command,
+    options: CommandOptions,
+    env: Vec<(OsString, OsString)>,
+    cwd: Option<PathBuf>,
+    filter: Option<Box<dyn OutputFilter>>,
+}
+
+impl SessionBuilder {
+    pub fn new(command: Command) -> Self {
+        Self {
+            command,
+            options: CommandOptions::default(),
+            env: Vec::new(),
+            cwd: None,
+            filter: None,
+        }
+    }
+
+    /// Replaces all options set so far.
+    pub fn options(mut self, options: CommandOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// See [`CommandOptions::size`].
+    pub fn size(mut self, size: Size) -> Self {
+        self.options.size = Some(size);
+        self
+    }
+
+    /// Sets an environment variable for the command, on top of the ones it
+    /// inherits.
+    pub fn env(mut self, key: impl AsRef<OsStr>, val: impl AsRef<OsStr>) -> Self {
+        self.env
+            .push((key.as_ref().to_owned(), val.as_ref().to_owned()));
+        self
+    }
+
+    /// Sets the command's working directory.
+    pub fn cwd(mut self, dir: impl AsRef<Path>) -> Self {
+        self.cwd = Some(dir.as_ref().to_owned());
+        self
+    }
+
+    /// See [`CommandOptions::input_buffer`].
+    pub fn input_buffer(mut self, input_buffer: usize) -> Self {
+        self.options.input_buffer = input_buffer;
+        self
+    }
+
+    /// See [`CommandOptions::kill_grace`].
+    pub fn kill_grace(mut self, kill_grace: Duration) -> Self {
+        self.options.kill_grace = kill_grace;
+        self
+    }
+
+    /// See [`CommandOptions::detect_title`].
+    pub fn detect_title(mut self, detect_title: bool) -> Self {
+        self.options.detect_title = detect_title;
+        self
+    }
+
+    /// See [`CommandOptions::detect_bell`].
+    pub fn detect_bell(mut self, detect_bell: bool) -> Self {
+        self.options.detect_bell = detect_bell;
+        self
+    }
+
+    /// See [`CommandOptions::output_limit`].
+    pub fn output_limit(mut self, limit: usize, policy: OverflowPolicy) -> Self {
+        self.options.output_limit = Some((limit, policy));
+        self
+    }
+
+    /// See [`CommandOptions::flow_control`].
+    pub fn flow_control(mut self, flow_control: FlowControl) -> Self {
+        self.options.flow_control = Some(flow_control);
+        self
+    }
+
+    /// See [`CommandOptions::write_timeout`].
+    pub fn write_timeout(mut self, timeout: Duration, policy: WriteTimeoutPolicy) -> Self {
+        self.options.write_timeout = Some((timeout, policy));
+        self
+    }
+
+    /// Passes the command's output through `filter`. Title and bell events
+    /// are picked out of the output before it is filtered.
+    pub fn output_filter(mut self, filter: impl OutputFilter + 'static) -> Self {
+        self.filter = Some(Box::new(filter));
+        self
+    }
+
+    /// Spawns the command.
+    pub fn build(self) -> Result<Session, Error> {
+        let command = configure(self.command, self.env, self.cwd);
+        let aborter = Arc::new(Notify::new());
+        let spawned = spawn(command, aborter.clone(), self.options)?;
+
+        let output = match self.filter {
+            Some(filter) => filter_output(spawned.output, filter),
+            None => spawned.output,
+        };
+
+        let (exit_tx, exit_rx) = oneshot::channel();
+        let mut exit_tx = Some(exit_tx);
+        let output = output
+            .inspect(move |item| {
+                if let CommandOutputItem::Exit(exit) = item
+                    && let Some(exit_tx) = exit_tx.take()
+                {
+                    exit_tx.send(*exit).ok();
+                }
+            })
+            .boxed();
+
+        Ok(Session {
+            output,
+            input: spawned.input,
+            handle: SessionHandle {
+                aborter,
+                pid: spawned.pid,
+            },
+            exit: Box::pin(async move { exit_rx.await.ok() }),
+        })
+    }
+}
+
+/// A running command, as started by [`SessionBuilder::build`].
+pub struct Session {
+    /// The command's output, ending after [`CommandOutputItem::Exit`].
+    pub output: CommandOutputStream,
+    /// Input, resizes and EOF for the command.
+    pub input: CommandInputSink,
+    pub handle: SessionHandle,
+    /// Resolves once the command has ended. The exit is learned from the
+    /// output stream, so this only resolves while `output` is being consumed.
+    pub exit: SessionExit,
+}
+
+/// Controls a running command; cheap to clone.
+#[derive(Debug, Clone)]
+pub struct SessionHandle {
+    aborter: Arc<Notify>,
+    pid: Option<u32>,
+}
+
+impl SessionHandle {
+    /// Stops the command, as described for [`crate::start_command`]. The
+    /// output stream then ends with [`CommandExit::Aborted`].
+    pub fn abort(&self) {
+        self.aborter.notify_one();
+    }
+
+    /// The command's process id, if it could be determined.
+    pub fn pid(&self) -> Option<u32> {
+        self.pid
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use futures_util::SinkExt;
+
+    use super::*;
+    use crate::CommandInputItem;
+
+    fn sh(script: &str) -> SessionBuilder {
+        SessionBuilder::new(Command::new("sh").args(["-c", script]))
+    }
+
+    /// Reads the session's output until the command ends.
+    async fn output(session: &mut Session) -> String {
+        let mut output = Vec::new();
+        let read = async {
+            while let Some(item) = session.output.next().await {
+                if let CommandOutputItem::Output(data) = item {
+                    output.extend_from_slice(&data);
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(10), read)
+            .await
+            .expect("command should end");
+        String::from_utf8_lossy(&output).into_owned()
+    }
+
+    #[tokio::test]
+    async fn sets_size() {
+        let mut session = sh("stty size").size(Size::new(12, 34)).build().unwrap();
+        assert!(output(&mut session).await.contains("12 34"));
+    }
+
+    #[tokio::test]
+    async fn sets_env_and_cwd() {
+        let mut session = sh("echo \"$RTTY_TEST:$(pwd)\"")
+            .env("RTTY_TEST", "hello")
+            .cwd("/")
+            .build()
+            .unwrap();
+        assert!(output(&mut session).await.contains("hello:/\r\n"));
+    }
+
+    #[tokio::test]
+    async fn delivers_input_through_a_small_buffer() {
+        let mut session = sh("read line; echo \"got $line\"")
+            .input_buffer(1)
+            .build()
+            .unwrap();
+        for input in ["a", "b", "c", "\r"] {
+            let item = CommandInputItem::InputString(input.to_string());
+            session.input.send(item).await.unwrap();
+        }
+        let output = output(&mut session).await;
+        assert!(output.contains("got abc"), "{output:?}");
+    }
+
+    #[tokio::test]
+    async fn reports_pid() {
+        let mut session = sh("echo \"pid $$\"").build().unwrap();
+        let pid = session.handle.pid().unwrap();
+        assert!(
+            output(&mut session)
+                .await
+                .contains(&format!("pid {pid}\r\n"))
+        );
+    }
+
+    #[tokio::test]
+    async fn exit_resolves_with_exit_code() {
+        let mut session = sh("exit 3").build().unwrap();
+        output(&mut session).await;
+        assert_eq!(session.exit.await, Some(CommandExit::Exited(3)));
+    }
+
+    #[tokio::test]
+    async fn abort_ends_command() {
+        let mut session = sh("sleep 100").build().unwrap();
+        session.handle.clone().abort();
+        output(&mut session).await;
+        assert_eq!(session.exit.await, Some(CommandExit::Aborted));
+    }
+}
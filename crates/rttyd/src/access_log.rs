@@ -0,0 +1,155 @@
+use std::{path::Path, sync::Arc};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{HeaderMap, header},
+    middleware::Next,
+    response::Response,
+};
+use http_body::Body;
+use time::{OffsetDateTime, format_description::FormatItem, macros::format_description};
+use tokio::sync::mpsc;
+
+use crate::{
+    log_writer,
+    peer::{self, PeerAddr},
+};
+
+/// Timestamp format of the Common Log Format, e.g. `10/Oct/2000:13:55:36 +0000`.
+const CLF_TIME: &[FormatItem<'_>] = format_description!(
+    "[day]/[month repr:short]/[year]:[hour]:[minute]:[second] [offset_hour sign:mandatory][offset_minute]"
+);
+
+/// Writes one line per HTTP request in the Combined Log Format.
+///
+/// Query strings are left out of the logged request line, as they may carry
+/// auth tokens.
+pub struct AccessLog {
+    tx: mpsc::UnboundedSender<String>,
+    trust_proxy: bool,
+}
+
+impl AccessLog {
+    /// Opens the log at `path` for appending, or stdout if `path` is `-`.
+    pub async fn open(path: &Path, trust_proxy: bool) -> std::io::Result<Arc<Self>> {
+        let tx = if path.as_os_str() == "-" {
+            log_writer::spawn(tokio::io::stdout(), "access log")
+        } else {
+            log_writer::spawn(log_writer::open_append(path).await?, "access log")
+        };
+        Ok(Arc::new(Self { tx, trust_proxy }))
+    }
+}
+
+/// Middleware logging each request once its response is ready.
+pub async fn log_request(
+    State(log): State<Arc<AccessLog>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let host = request
+        .extensions()
+        .get::<ConnectInfo<PeerAddr>>()
+        .map(|ConnectInfo(peer)| {
+            peer::client_ip(*peer, request.headers(), log.trust_proxy).to_string()
+        })
+        .unwrap_or_else(|| "-".to_string());
+    let request_line = format!(
+        "{} {} {:?}",
+        request.method(),
+        request.uri().path(),
+        request.version()
+    );
+    let referer = quoted_header(request.headers(), header::REFERER);
+    let user_agent = quoted_header(request.headers(), header::USER_AGENT);
+
+    let response = next.run(request).await;
+
+    let line = log_line(
+        &host,
+        OffsetDateTime::now_utc(),
+        &request_line,
+        response.status().as_u16(),
+        response.body().size_hint().exact(),
+        &referer,
+        &user_agent,
+    );
+    log.tx.send(line).ok();
+    response
+}
+
+/// Formats a request as a line of the Combined Log Format.
+fn log_line(
+    host: &str,
+    time: OffsetDateTime,
+    request_line: &str,
+    status: u16,
+    bytes: Option<u64>,
+    referer: &str,
+    user_agent: &str,
+) -> String {
+    let time = time
+        .format(CLF_TIME)
+        .expect("timestamps with an offset are formattable");
+    let bytes = bytes.map_or_else(|| "-".to_string(), |len| len.to_string());
+    format!("{host} - - [{time}] \"{request_line}\" {status} {bytes} {referer} {user_agent}\n")
+}
+
+/// Formats a header value as a quoted log field, or `"-"` if it is missing.
+fn quoted_header(headers: &HeaderMap, name: header::HeaderName) -> String {
+    match headers.get(name) {
+        Some(value) => format!("\"{}\"", value.as_bytes().escape_ascii()),
+        None => "\"-\"".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+    use time::macros::datetime;
+
+    use super::*;
+
+    #[test]
+    fn formats_combined_log_lines() {
+        let line = log_line(
+            "192.0.2.1",
+            datetime!(2000-10-10 13:55:36 UTC),
+            "GET /ws HTTP/1.1",
+            101,
+            Some(0),
+            "\"https://example.com/\"",
+            "\"curl/8.0\"",
+        );
+        assert_eq!(
+            line,
+            "192.0.2.1 - - [10/Oct/2000:13:55:36 +0000] \"GET /ws HTTP/1.1\" 101 0 \
+             \"https://example.com/\" \"curl/8.0\"\n"
+        );
+        let line = log_line(
+            "-",
+            datetime!(2000-10-10 13:55:36 UTC),
+            "GET / HTTP/1.1",
+            200,
+            None,
+            "\"-\"",
+            "\"-\"",
+        );
+        assert!(
+            line.ends_with("\"GET / HTTP/1.1\" 200 - \"-\" \"-\"\n"),
+            "{line}"
+        );
+    }
+
+    #[test]
+    fn quotes_and_escapes_headers() {
+        let mut headers = HeaderMap::new();
+        let agent = HeaderValue::from_bytes(b"evil\" \\agent\xff").unwrap();
+        headers.insert(header::USER_AGENT, agent);
+        assert_eq!(
+            quoted_header(&headers, header::USER_AGENT),
+            r#""evil\" \\agent\xff""#
+        );
+        assert_eq!(quoted_header(&headers, header::REFERER), "\"-\"");
+    }
+}
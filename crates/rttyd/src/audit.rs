@@ -0,0 +1,155 @@
+use std::path::Path;
+
+use time::{OffsetDateTime, format_description::well_known::Rfc3339};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use crate::log_writer;
+
+/// Written in place of input typed at a password prompt with --audit-redact.
+const REDACTED: &str = "[redacted]";
+
+/// Output that makes the following line of input secret.
+const SECRET_PROMPTS: &[&[u8]] = &[b"password", b"passphrase", b"pin:"];
+
+/// Appends client input to a file, one line per input message:
+/// `<RFC 3339 timestamp> <session id> <input>`, with the input escaped so
+/// control characters and non-ASCII bytes show up as `\r`, `\x1b` etc.
+///
+/// Writes happen on a background task, so recording never blocks a session.
+#[derive(Clone)]
+pub struct AuditLog {
+    tx: mpsc::UnboundedSender<String>,
+}
+
+impl AuditLog {
+    pub async fn open(path: &Path) -> std::io::Result<Self> {
+        let file = log_writer::open_append(path).await?;
+        Ok(Self {
+            tx: log_writer::spawn(file, "audit log"),
+        })
+    }
+
+    fn record(&self, session_id: Uuid, input: &str) {
+        let timestamp = OffsetDateTime::now_utc()
+            .format(&Rfc3339)
+            .expect("UTC timestamps are formattable");
+        self.tx
+            .send(format!("{timestamp} {session_id} {input}\n"))
+            .ok();
+    }
+}
+
+/// Audit trail of a single session.
+pub struct SessionAudit {
+    log: AuditLog,
+    session_id: Uuid,
+    /// Mask input typed after a password-like prompt.
+    redact: bool,
+    /// Input is being masked until the next line ends.
+    masking: bool,
+}
+
+impl SessionAudit {
+    pub fn new(log: AuditLog, session_id: Uuid, redact: bool) -> Self {
+        Self {
+            log,
+            session_id,
+            redact,
+            masking: false,
+        }
+    }
+
+    /// Looks at command output for a password prompt.
+    pub fn output(&mut self, output: &[u8]) {
+        if self.redact && !self.masking {
+            let output = output.to_ascii_lowercase();
+            self.masking = SECRET_PROMPTS
+                .iter()
+                .any(|prompt| output.windows(prompt.len()).any(|w| w == *prompt));
+        }
+    }
+
+    /// Records input sent to the command.
+    pub fn input(&mut self, input: &[u8]) {
+        if !self.masking {
+            self.log
+                .record(self.session_id, &input.escape_ascii().to_string());
+            return;
+        }
+        // keep masking until the secret has been entered
+        match input.iter().position(|b| matches!(b, b'\r' | b'\n')) {
+            Some(end) => {
+                self.masking = false;
+                let rest = input[end + 1..].escape_ascii();
+                self.log.record(
+                    self.session_id,
+                    &format!("{REDACTED}{}{rest}", input[end].escape_ascii()),
+                );
+            }
+            None => self.log.record(self.session_id, REDACTED),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn audit(redact: bool) -> (SessionAudit, mpsc::UnboundedReceiver<String>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let audit = SessionAudit::new(AuditLog { tx }, Uuid::nil(), redact);
+        (audit, rx)
+    }
+
+    /// The input part of each recorded line.
+    fn inputs(rx: &mut mpsc::UnboundedReceiver<String>) -> Vec<String> {
+        let mut inputs = Vec::new();
+        while let Ok(line) = rx.try_recv() {
+            let (_, input) = line.split_once(&format!(" {} ", Uuid::nil())).unwrap();
+            inputs.push(input.strip_suffix('\n').unwrap().to_string());
+        }
+        inputs
+    }
+
+    #[test]
+    fn records_timestamp_session_and_input() {
+        let (mut audit, mut rx) = audit(false);
+        audit.input(b"ls");
+        let line = rx.try_recv().unwrap();
+        let (timestamp, rest) = line.split_once(' ').unwrap();
+        assert!(
+            timestamp.contains('T') && timestamp.ends_with('Z'),
+            "{line}"
+        );
+        assert_eq!(rest, format!("{} ls\n", Uuid::nil()));
+    }
+
+    #[test]
+    fn escapes_control_and_non_ascii_bytes() {
+        let (mut audit, mut rx) = audit(false);
+        audit.input("\x1b[A\u{e9}\\\r".as_bytes());
+        assert_eq!(inputs(&mut rx), [r"\x1b[A\xc3\xa9\\\r"]);
+    }
+
+    #[test]
+    fn redacts_input_after_password_prompt() {
+        let (mut audit, mut rx) = audit(true);
+        audit.output(b"[sudo] Password for root: ");
+        audit.input(b"hun");
+        audit.input(b"ter2\rls\r");
+        audit.input(b"whoami\r");
+        assert_eq!(
+            inputs(&mut rx),
+            ["[redacted]", r"[redacted]\rls\r", r"whoami\r"]
+        );
+    }
+
+    #[test]
+    fn records_input_after_prompt_without_redact() {
+        let (mut audit, mut rx) = audit(false);
+        audit.output(b"Enter passphrase: ");
+        audit.input(b"secret\r");
+        assert_eq!(inputs(&mut rx), [r"secret\r"]);
+    }
+}
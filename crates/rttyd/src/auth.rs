@@ -0,0 +1,86 @@
+use std::{collections::HashSet, sync::Mutex};
+
+/// Checks the `token` query parameter of WebSocket upgrades.
+#[derive(Debug)]
+pub struct TokenAuth {
+    tokens: Vec<String>,
+    one_time: bool,
+    used: Mutex<HashSet<String>>,
+}
+
+impl TokenAuth {
+    pub fn new(tokens: Vec<String>, one_time: bool) -> Self {
+        Self {
+            tokens,
+            one_time,
+            used: Mutex::default(),
+        }
+    }
+
+    /// Returns whether `presented` matches a configured token that may still
+    /// be used, without using it up.
+    pub fn check(&self, presented: Option<&str>) -> bool {
+        match self.matching(presented) {
+            Some(token) if self.one_time => !self.used.lock().unwrap().contains(token),
+            Some(_) => true,
+            None => false,
+        }
+    }
+
+    /// Like [`TokenAuth::check`], but in one-time mode also consumes the
+    /// token so it is rejected from then on. Call it once the connection is
+    /// accepted, so a token isn't used up by one that is turned away.
+    pub fn consume(&self, presented: Option<&str>) -> bool {
+        match self.matching(presented) {
+            Some(token) if self.one_time => self.used.lock().unwrap().insert(token.clone()),
+            Some(_) => true,
+            None => false,
+        }
+    }
+
+    fn matching(&self, presented: Option<&str>) -> Option<&String> {
+        let presented = presented?;
+        // compare against every token so timing doesn't reveal which matched
+        self.tokens.iter().fold(None, |matched, token| {
+            if constant_time_eq(token.as_bytes(), presented.as_bytes()) {
+                Some(token)
+            } else {
+                matched
+            }
+        })
+    }
+}
+
+/// Compares two byte strings in time independent of where they differ.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_leaves_one_time_tokens_unused() {
+        let auth = TokenAuth::new(vec!["a".into(), "b".into()], true);
+        assert!(auth.check(Some("a")));
+        assert!(auth.check(Some("a")));
+        assert!(auth.consume(Some("a")));
+        assert!(!auth.check(Some("a")));
+        assert!(!auth.consume(Some("a")));
+        assert!(auth.consume(Some("b")));
+    }
+
+    #[test]
+    fn rejects_wrong_or_missing_tokens() {
+        let auth = TokenAuth::new(vec!["secret".into()], false);
+        assert!(!auth.check(None));
+        assert!(!auth.check(Some("secre")));
+        assert!(!auth.consume(Some("secret2")));
+        assert!(auth.consume(Some("secret")));
+        assert!(auth.consume(Some("secret")));
+    }
+}
@@ -0,0 +1,240 @@
+use std::{
+    ffi::OsString,
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+/// What to run for each session.
+#[derive(Clone, Debug)]
+pub enum CommandSpec {
+    /// A script run as `<shell> -c <script>`.
+    Shell { shell: String, script: String },
+    /// A program and its arguments, executed directly without a shell so no
+    /// metacharacters are interpreted.
+    Argv(Vec<String>),
+}
+
+/// Environment commands are started with.
+#[derive(Clone, Debug, Default)]
+pub struct CommandEnv {
+    /// Start from an empty environment rather than rttyd's own.
+    pub clear: bool,
+    /// Set on top, in order.
+    pub vars: Vec<(OsString, OsString)>,
+}
+
+/// Variables kept by `--env-clear`, as commands rarely work without them.
+pub const BASE_ENV: &[&str] = &["PATH", "TERM", "HOME"];
+
+/// Shell used when none is configured.
+pub const DEFAULT_SHELL: &str = if cfg!(windows) { "cmd" } else { "sh" };
+
+impl CommandSpec {
+    /// The program that gets executed.
+    pub fn program(&self) -> &str {
+        match self {
+            Self::Shell { shell, .. } => shell,
+            Self::Argv(argv) => &argv[0],
+        }
+    }
+
+    /// Checks that the program exists and is executable, so a typo fails at
+    /// startup rather than on every connection.
+    pub fn check_program(&self) -> Result<(), String> {
+        let program = self.program();
+        if find_executable(Path::new(program)).is_some() {
+            Ok(())
+        } else if program.contains(std::path::is_separator) {
+            Err(format!("`{program}` does not exist or is not executable"))
+        } else {
+            Err(format!("`{program}` was not found on PATH"))
+        }
+    }
+
+    #[cfg(unix)]
+    pub fn to_command(&self, env: &CommandEnv) -> rtty::Command {
+        let mut command = match self {
+            Self::Shell { shell, script } => rtty::Command::new(shell).arg("-c").arg(script),
+            Self::Argv(argv) => rtty::Command::new(&argv[0]).args(&argv[1..]),
+        };
+        if env.clear {
+            command = command.env_clear();
+        }
+        command.envs(env.vars.iter().cloned())
+    }
+
+    #[cfg(windows)]
+    pub fn to_command(&self, env: &CommandEnv) -> rtty::Command {
+        let mut command = match self {
+            Self::Shell { shell, script } => {
+                let mut command = rtty::Command::new(shell);
+                let is_cmd = std::path::Path::new(shell)
+                    .file_stem()
+                    .is_some_and(|stem| stem.eq_ignore_ascii_case("cmd"));
+                command.arg(if is_cmd { "/C" } else { "-c" });
+                command.arg(script);
+                command
+            }
+            Self::Argv(argv) => {
+                let mut command = rtty::Command::new(&argv[0]);
+                command.args(&argv[1..]);
+                command
+            }
+        };
+        if env.clear {
+            command.env_clear();
+        }
+        for (key, val) in &env.vars {
+            command.env(key, val);
+        }
+        command
+    }
+}
+
+/// Resolves `program` like the OS does when spawning it: paths are used as
+/// they are, bare names are looked up on `PATH`.
+fn find_executable(program: &Path) -> Option<PathBuf> {
+    if program.components().count() > 1 {
+        return is_executable(program).then(|| program.to_path_buf());
+    }
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path)
+        .map(|dir| dir.join(program))
+        .find_map(|candidate| {
+            if is_executable(&candidate) {
+                return Some(candidate);
+            }
+            // Windows runs `cmd` as `cmd.exe`
+            #[cfg(windows)]
+            if candidate.extension().is_none() {
+                let pathext = std::env::var("PATHEXT").unwrap_or(".COM;.EXE;.BAT;.CMD".into());
+                return pathext
+                    .split(';')
+                    .filter(|ext| !ext.is_empty())
+                    .map(|ext| candidate.with_extension(&ext[1..]))
+                    .find(|candidate| is_executable(candidate));
+            }
+            None
+        })
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    std::fs::metadata(path)
+        .is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(windows)]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+/// A named command served on `/ws/<name>`, written as `<NAME>=<COMMAND>`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Route {
+    pub name: String,
+    pub command: String,
+}
+
+impl FromStr for Route {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, command) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected <NAME>=<COMMAND>, got `{s}`"))?;
+        if name.is_empty() || name.contains('/') {
+            return Err(format!("invalid route name `{name}`"));
+        }
+        if command.trim().is_empty() {
+            return Err(format!("route `{name}` has no command"));
+        }
+        Ok(Self {
+            name: name.to_string(),
+            command: command.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for Route {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.command)
+    }
+}
+
+/// An environment variable set for commands, written as `<KEY>=<VALUE>`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EnvVar {
+    pub key: String,
+    pub value: String,
+}
+
+impl FromStr for EnvVar {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (key, value) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected <KEY>=<VALUE>, got `{s}`"))?;
+        if key.is_empty() || key.contains('\0') || value.contains('\0') {
+            return Err(format!("invalid environment variable `{s}`"));
+        }
+        Ok(Self {
+            key: key.to_string(),
+            value: value.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for EnvVar {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.key, self.value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_routes() {
+        let route: Route = "logs=tail -f a=b.log".parse().unwrap();
+        assert_eq!(route.name, "logs");
+        assert_eq!(route.command, "tail -f a=b.log");
+        assert_eq!(route.to_string(), "logs=tail -f a=b.log");
+    }
+
+    #[test]
+    fn rejects_malformed_routes() {
+        let err = "logs".parse::<Route>().unwrap_err();
+        assert_eq!(err, "expected <NAME>=<COMMAND>, got `logs`");
+        let err = "=top".parse::<Route>().unwrap_err();
+        assert_eq!(err, "invalid route name ``");
+        let err = "a/b=top".parse::<Route>().unwrap_err();
+        assert_eq!(err, "invalid route name `a/b`");
+        let err = "logs= ".parse::<Route>().unwrap_err();
+        assert_eq!(err, "route `logs` has no command");
+    }
+
+    fn shell(shell: &str) -> CommandSpec {
+        CommandSpec::Shell {
+            shell: shell.to_string(),
+            script: "top".to_string(),
+        }
+    }
+
+    #[test]
+    fn check_program_rejects_bogus_shells() {
+        let err = shell("rttyd-no-such-shell").check_program().unwrap_err();
+        assert_eq!(err, "`rttyd-no-such-shell` was not found on PATH");
+        let err = shell("/no/such/sh").check_program().unwrap_err();
+        assert_eq!(err, "`/no/such/sh` does not exist or is not executable");
+        // a directory isn't a program
+        let dir = std::env::temp_dir();
+        assert!(shell(dir.to_str().unwrap()).check_program().is_err());
+        assert!(shell(DEFAULT_SHELL).check_program().is_ok());
+    }
+}
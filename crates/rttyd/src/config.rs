@@ -0,0 +1,218 @@
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use clap::{ArgAction, ArgMatches, CommandFactory, parser::ValueSource};
+
+use crate::RttydArgs;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("failed to read config file {path}: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("invalid config file {path}: {source}")]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    #[error("unknown keys in config file {path}: {}", keys.join(", "))]
+    UnknownKeys { path: PathBuf, keys: Vec<String> },
+    #[error("invalid value for `{key}` in config file {path}")]
+    InvalidValue { path: PathBuf, key: String },
+}
+
+/// Merges the TOML config file at `path` into the command line `argv`,
+/// returning the arguments to parse again in its place.
+///
+/// File keys are the long CLI flags (e.g. `kill-grace = 10`,
+/// `auth-token = ["a", "b"]`), plus `command` for the positional COMMAND.
+/// Values given explicitly on the command line take precedence over the
+/// file, which in turn takes precedence over the built-in defaults. As the
+/// result is parsed like any command line, conflicting or missing options
+/// are reported no matter which side they come from.
+pub fn merge_config_file(
+    argv: Vec<OsString>,
+    matches: &ArgMatches,
+    path: &Path,
+) -> Result<Vec<OsString>, ConfigError> {
+    let content = std::fs::read_to_string(path).map_err(|source| ConfigError::Read {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    merge_config(argv, matches, path, &content)
+}
+
+fn merge_config(
+    mut argv: Vec<OsString>,
+    matches: &ArgMatches,
+    path: &Path,
+    content: &str,
+) -> Result<Vec<OsString>, ConfigError> {
+    let file: toml::Table = toml::from_str(content).map_err(|source| ConfigError::Parse {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    let invalid = |key: &str| ConfigError::InvalidValue {
+        path: path.to_path_buf(),
+        key: key.to_string(),
+    };
+
+    let command = RttydArgs::command();
+    let mut options = Vec::new();
+    let mut positional = Vec::new();
+    let mut unknown = Vec::new();
+    for (key, value) in &file {
+        let arg = if key == "command" {
+            command
+                .get_positionals()
+                .find(|arg| arg.get_id() == "command")
+        } else {
+            command
+                .get_arguments()
+                .filter(|arg| !matches!(arg.get_id().as_str(), "config" | "help" | "version"))
+                .find(|arg| arg.get_long() == Some(key.as_str()))
+        };
+        let Some(arg) = arg else {
+            unknown.push(key.clone());
+            continue;
+        };
+        if matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine) {
+            continue;
+        }
+        if arg.is_positional() {
+            positional = values(value).ok_or_else(|| invalid(key))?;
+        } else if matches!(arg.get_action(), ArgAction::SetTrue) {
+            match value {
+                toml::Value::Boolean(true) => options.push(format!("--{key}")),
+                toml::Value::Boolean(false) => {}
+                _ => return Err(invalid(key)),
+            }
+        } else {
+            let values = values(value).ok_or_else(|| invalid(key))?;
+            options.extend(values.into_iter().map(|value| format!("--{key}={value}")));
+        }
+    }
+    if !unknown.is_empty() {
+        return Err(ConfigError::UnknownKeys {
+            path: path.to_path_buf(),
+            keys: unknown,
+        });
+    }
+
+    let rest = argv.split_off(argv.len().min(1));
+    let separated = rest.iter().any(|arg| arg == "--");
+    argv.extend(options.into_iter().map(OsString::from));
+    argv.extend(rest);
+    if !positional.is_empty() {
+        if !separated {
+            argv.push("--".into());
+        }
+        argv.extend(positional.into_iter().map(OsString::from));
+    }
+    Ok(argv)
+}
+
+/// The values of a key: a single scalar, or an array of them for options
+/// that may be repeated.
+fn values(value: &toml::Value) -> Option<Vec<String>> {
+    let scalar = |value: &toml::Value| match value {
+        toml::Value::String(s) => Some(s.clone()),
+        toml::Value::Integer(n) => Some(n.to_string()),
+        toml::Value::Float(n) => Some(n.to_string()),
+        toml::Value::Boolean(b) => Some(b.to_string()),
+        toml::Value::Datetime(_) | toml::Value::Array(_) | toml::Value::Table(_) => None,
+    };
+    match value {
+        toml::Value::Array(values) => values.iter().map(scalar).collect(),
+        value => scalar(value).map(|value| vec![value]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::FromArgMatches;
+
+    use super::*;
+
+    fn load(cli: &[&str], file: &str) -> Result<RttydArgs, String> {
+        let argv = std::iter::once("rttyd")
+            .chain(cli.iter().copied())
+            .map(OsString::from)
+            .collect::<Vec<_>>();
+        let matches = RttydArgs::command()
+            .try_get_matches_from(&argv)
+            .map_err(|err| err.to_string())?;
+        let argv = merge_config(argv, &matches, Path::new("rttyd.toml"), file)
+            .map_err(|err| err.to_string())?;
+        let matches = RttydArgs::command()
+            .try_get_matches_from(argv)
+            .map_err(|err| err.to_string())?;
+        RttydArgs::from_arg_matches(&matches).map_err(|err| err.to_string())
+    }
+
+    #[test]
+    fn file_overrides_defaults() {
+        let args = load(&["-c", "rttyd.toml"], "port = 9000\nkill-grace = 10\n").unwrap();
+        assert_eq!(args.port, 9000);
+        assert_eq!(args.kill_grace, 10);
+        assert_eq!(args.host, ["127.0.0.1"]);
+    }
+
+    #[test]
+    fn command_line_overrides_file() {
+        let file = "port = 9000\nhost = [\"0.0.0.0\", \"::\"]\ncommand = \"top\"\n";
+        let args = load(&["-c", "rttyd.toml", "-p", "7000", "--", "htop"], file).unwrap();
+        assert_eq!(args.port, 7000);
+        assert_eq!(args.host, ["0.0.0.0", "::"]);
+        assert_eq!(args.command, ["htop"]);
+    }
+
+    #[test]
+    fn keys_are_long_flag_names() {
+        let file = "auth-token = [\"a\", \"b\"]\nroute = \"logs=tail -f log\"\n\
+                    command = [\"top\", \"-d\", \"1\"]\njson-startup = true\n";
+        let args = load(&["-c", "rttyd.toml"], file).unwrap();
+        assert_eq!(args.auth_tokens, ["a", "b"]);
+        assert_eq!(args.routes.len(), 1);
+        assert_eq!(args.routes[0].name, "logs");
+        assert_eq!(args.routes[0].command, "tail -f log");
+        assert_eq!(args.command, ["top", "-d", "1"]);
+        assert!(args.json_startup);
+    }
+
+    #[test]
+    fn field_names_are_unknown_keys() {
+        let err = load(
+            &["-c", "rttyd.toml"],
+            "auth-tokens = [\"a\"]\nroutes = []\n",
+        )
+        .unwrap_err();
+        assert!(err.contains("unknown keys"), "{err}");
+        assert!(err.contains("auth-tokens, routes"), "{err}");
+    }
+
+    #[test]
+    fn validates_against_command_line() {
+        #[cfg(unix)]
+        {
+            let err = load(
+                &["-c", "rttyd.toml", "-H", "::"],
+                "unix-socket = \"/tmp/s\"\n",
+            )
+            .unwrap_err();
+            assert!(err.contains("cannot be used with"), "{err}");
+        }
+        let err = load(&["-c", "rttyd.toml"], "one-time-tokens = true\n").unwrap_err();
+        assert!(err.contains("--auth-token"), "{err}");
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        let err = load(&["-c", "rttyd.toml"], "json-startup = \"yes\"\n").unwrap_err();
+        assert!(err.contains("invalid value for `json-startup`"), "{err}");
+        let err = load(&["-c", "rttyd.toml"], "port = \"none\"\n").unwrap_err();
+        assert!(err.contains("--port"), "{err}");
+    }
+}
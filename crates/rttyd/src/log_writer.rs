@@ -0,0 +1,40 @@
+use std::path::Path;
+
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::mpsc,
+};
+use tracing::warn;
+
+/// Opens the log file at `path` for appending, creating it if needed.
+pub async fn open_append(path: &Path) -> std::io::Result<tokio::fs::File> {
+    tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+}
+
+/// Spawns a task writing the lines sent on the returned channel to `out`.
+///
+/// Each line is flushed as soon as it is written, so it isn't lost if rttyd
+/// is killed. `name` says which log failed to be written in warnings.
+pub fn spawn<W>(mut out: W, name: &'static str) -> mpsc::UnboundedSender<String>
+where
+    W: AsyncWrite + Send + Unpin + 'static,
+{
+    let (tx, mut rx) = mpsc::unbounded_channel::<String>();
+    tokio::spawn(async move {
+        while let Some(line) = rx.recv().await {
+            if let Err(err) = async {
+                out.write_all(line.as_bytes()).await?;
+                out.flush().await
+            }
+            .await
+            {
+                warn!("Failed to write {name}: {err}");
+            }
+        }
+    });
+    tx
+}
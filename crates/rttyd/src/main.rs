@@ -1,29 +1,89 @@
+mod access_log;
+mod audit;
+mod auth;
+mod command;
+mod config;
+mod log_writer;
+mod peer;
+mod rate_limit;
+mod scrollback;
+mod sessions;
+
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::path::PathBuf;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
 
-use axum::extract::ws::Message;
-use axum::http::{Response, StatusCode, Uri, header};
-use axum::{Router, extract::WebSocketUpgrade, response::IntoResponse, routing::get};
+use axum::body::{Body, Bytes};
+use axum::extract::ws::{CloseFrame, Message, close_code, rejection::WebSocketUpgradeRejection};
+use axum::extract::{ConnectInfo, Path, Query, State};
+use axum::http::{HeaderMap, HeaderValue, Response, StatusCode, Uri, header};
+use axum::{
+    Json, Router,
+    extract::WebSocketUpgrade,
+    middleware,
+    response::IntoResponse,
+    routing::{get, post},
+};
 use base64::Engine;
-use clap::{Parser, command, value_parser};
-use futures_util::{SinkExt, StreamExt};
-use pty_process::Command;
-use rtty::{CommandInputItem, CommandOutputItem, start_command};
+use clap::{CommandFactory, FromArgMatches, Parser, ValueEnum, value_parser};
+use command::{BASE_ENV, CommandEnv, CommandSpec, DEFAULT_SHELL, EnvVar, Route};
+use futures_util::{
+    FutureExt, SinkExt, StreamExt,
+    stream::{SplitSink, SplitStream},
+};
+use rtty::{
+    CommandExit, CommandInputItem, CommandInputSink, CommandOptions, CommandOutputItem,
+    CommandOutputStream, FlowControl, OverflowPolicy, WriteTimeoutPolicy, start_command,
+};
 use rust_embed::Embed;
+use serde::{Deserialize, Serialize};
 use tokio::net::TcpListener;
-use tokio::sync::Notify;
-use tracing::{Level, info, warn};
+use tokio::sync::{Notify, broadcast, watch};
+use tokio_util::sync::CancellationToken;
+use tracing::{Instrument, Level, Span, info, info_span, warn};
+use uuid::Uuid;
+
+use crate::access_log::AccessLog;
+use crate::audit::{AuditLog, SessionAudit};
+use crate::auth::TokenAuth;
+use crate::peer::PeerAddr;
+use crate::rate_limit::{ClientLimiter, OutputThrottle, Rate, RateLimiter};
+use crate::scrollback::Scrollback;
+use crate::sessions::{AttachError, Registration, SessionRegistry};
 
-#[cfg(not(any(target_os = "macos", target_os = "windows", target_arch = "arm")))]
+#[cfg(all(
+    feature = "jemalloc",
+    not(any(target_os = "macos", target_os = "windows", target_arch = "arm"))
+))]
 use tikv_jemallocator::Jemalloc;
 
-#[cfg(not(any(target_os = "macos", target_os = "windows", target_arch = "arm")))]
+#[cfg(all(
+    feature = "jemalloc",
+    not(any(target_os = "macos", target_os = "windows", target_arch = "arm"))
+))]
 #[global_allocator]
 static GLOBAL: Jemalloc = Jemalloc;
 
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None, long_version = env!("PKG_LONG_VERSION"))]
+#[command(
+  version,
+  about,
+  long_about = None,
+  long_version = env!("PKG_LONG_VERSION"),
+  subcommand_negates_reqs = true,
+  subcommand_value_name = "TASK"
+)]
 pub struct RttydArgs {
+    #[command(subcommand)]
+    pub subcommand: Option<RttydCommand>,
+
+    /// Read options from a TOML file; flags given on the command line win
+    #[arg(long, short = 'c')]
+    pub config: Option<PathBuf>,
+
     #[arg(
       short = 'v', long,
       value_parser = ["trace", "debug", "info", "warn", "error"],
@@ -31,19 +91,597 @@ pub struct RttydArgs {
     )]
     pub verbosity: String,
 
+    /// Name shown by the web page, e.g. to tell several instances apart
+    #[arg(long)]
+    pub title: Option<String>,
+
+    /// Address to listen on; may be repeated to listen on several, e.g.
+    /// `-H 127.0.0.1 -H ::1`
     #[arg(long, short = 'H', default_value = "127.0.0.1")]
-    pub host: String,
+    pub host: Vec<String>,
 
     #[arg(long, short = 'p', value_parser = value_parser!(u16), default_value = "28888")]
     pub port: u16,
 
-    pub command: String,
+    /// Listen on a Unix domain socket at this path instead of TCP
+    #[cfg(unix)]
+    #[arg(long, conflicts_with_all = ["host", "port"])]
+    pub unix_socket: Option<PathBuf>,
+
+    /// Permissions (octal) applied to the --unix-socket file
+    #[cfg(unix)]
+    #[arg(long, value_parser = parse_mode, default_value = "660", requires = "unix_socket")]
+    pub unix_socket_mode: u32,
+
+    /// Write rttyd's process id to PATH, removing it again on shutdown
+    #[arg(long, value_name = "PATH")]
+    pub pid_file: Option<PathBuf>,
+
+    /// Announce readiness as a JSON line on stdout (`{"event":"listening",
+    /// "url":...,"version":...,"pid":...}`) instead of a human-readable one
+    #[arg(long)]
+    pub json_startup: bool,
+
+    /// Seconds to wait after SIGTERM before killing an aborted command
+    #[arg(long, value_parser = value_parser!(u64), default_value = "5")]
+    pub kill_grace: u64,
+
+    /// Number of pending input messages buffered per session; when full,
+    /// reading from the WebSocket pauses until the command catches up
+    #[arg(
+      long,
+      value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+      default_value = "200"
+    )]
+    pub input_buffer: usize,
+
+    /// Take the client address from the last X-Forwarded-For entry, as
+    /// appended by the reverse proxy in front of rttyd
+    #[arg(long)]
+    pub trust_proxy: bool,
+
+    /// Accept cleartext HTTP/2 (h2c with prior knowledge), e.g. from a
+    /// reverse proxy; WebSockets still upgrade over HTTP/1.1
+    #[arg(long)]
+    pub http2_cleartext: bool,
+
+    /// Answer page requests (`Accept: text/html`) for paths without an
+    /// asset with index.html, for frontends doing client-side routing
+    #[arg(long)]
+    pub spa: bool,
+
+    /// Allow each client address at most N new connections per SECONDS
+    #[arg(long, value_name = "N/SECONDS")]
+    pub rate_limit: Option<Rate>,
+
+    /// Allow each client address at most N sessions at a time
+    #[arg(
+      long,
+      value_name = "N",
+      value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    pub max_clients_per_ip: Option<usize>,
+
+    /// Require clients to connect with `/ws?token=<TOKEN>`; may be repeated
+    #[arg(long = "auth-token", value_name = "TOKEN")]
+    pub auth_tokens: Vec<String>,
+
+    /// Accept each --auth-token only once
+    #[arg(long, requires = "auth_tokens")]
+    pub one_time_tokens: bool,
+
+    /// Accept `POST /sessions/<ID>/input` requests that write the request
+    /// body to a session's terminal; callers authenticate with
+    /// `Authorization: Bearer <TOKEN>` using an --auth-token, which this
+    /// doesn't use up under --one-time-tokens
+    #[arg(long, requires = "auth_tokens")]
+    pub enable_control_api: bool,
+
+    /// Log every HTTP request to this file (`-` for stdout) in the Combined
+    /// Log Format
+    #[arg(long, value_name = "PATH")]
+    pub access_log: Option<PathBuf>,
+
+    /// Append everything clients type to this file, with timestamps and
+    /// session ids
+    #[arg(long, value_name = "PATH")]
+    pub audit_log: Option<PathBuf>,
+
+    /// Leave input typed after a password prompt out of the --audit-log
+    #[arg(long, requires = "audit_log")]
+    pub audit_redact: bool,
+
+    /// Keep the command running when its client disconnects, so a client can
+    /// reattach with `/ws?session=<ID>` (the ID is sent as a `c;<ID>` message
+    /// on connect and in the x-session-id header of the upgrade response; the
+    /// web page keeps it in its URL)
+    #[arg(long)]
+    pub detach_on_disconnect: bool,
+
+    /// Abort a detached command after this many seconds without a client
+    #[arg(
+      long,
+      value_name = "SECONDS",
+      value_parser = value_parser!(u64).range(1..),
+      requires = "detach_on_disconnect"
+    )]
+    pub detach_timeout: Option<u64>,
+
+    /// Bytes of recent output kept per session, replayed on reattach and
+    /// sent on request; clients may ask for less with `a;<BYTES>`
+    #[arg(long, value_name = "BYTES", default_value = "65536")]
+    pub scrollback_bytes: usize,
+
+    /// Let more clients join a running session with `/ws?session=<ID>` as
+    /// read-only viewers; they get its scrollback, then its live output,
+    /// while only the client that started it can type. The ID is sent as a
+    /// `c;<ID>` message, and the web page's URL then works as a share link
+    #[arg(long)]
+    pub shared: bool,
+
+    /// Serve a single session, then shut the server down, exiting with the
+    /// command's exit code (125 if the client left before the command exited)
+    #[arg(long)]
+    pub once: bool,
+
+    /// Send a `6;` status message with byte counters every SECONDS
+    #[arg(long, value_name = "SECONDS", value_parser = value_parser!(u64).range(1..))]
+    pub status_interval: Option<u64>,
+
+    /// Send an empty WebSocket ping every SECONDS, so proxies don't drop
+    /// connections that see no output for a while
+    #[arg(long, value_name = "SECONDS", value_parser = value_parser!(u64).range(1..))]
+    pub keepalive: Option<u64>,
+
+    /// Largest WebSocket message accepted from a client, in bytes; larger
+    /// ones close the connection. Base64 (`0;`) input decodes to at most 3/4
+    /// of this
+    #[arg(
+      long,
+      value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+      default_value = "1048576"
+    )]
+    pub max_message_bytes: usize,
+
+    /// Text written to the terminal when a client connects, before any
+    /// command output; `@PATH` reads it from a file
+    #[arg(long, value_name = "STRING|@FILE")]
+    pub banner: Option<String>,
+
+    /// Input typed into each new session's command before the client's, e.g.
+    /// `'cd /var/log\n'`; escapes (`\n`, `\r`, `\t`, `\e`, `\\`, `\xHH`)
+    /// are expanded and `@PATH` reads it from a file
+    #[arg(long, value_name = "STRING|@FILE")]
+    pub init_input: Option<String>,
+
+    /// Shell used to run a single-argument COMMAND as `<SHELL> -c <COMMAND>`
+    #[arg(long, default_value = DEFAULT_SHELL)]
+    pub shell: String,
+
+    /// Execute a single-argument COMMAND (and route commands, split on
+    /// whitespace) directly instead of through the shell
+    #[arg(long, conflicts_with = "shell")]
+    pub no_shell: bool,
+
+    /// Start the command again when it exits while the client is still
+    /// connected, instead of ending the session
+    #[arg(long)]
+    pub restart: bool,
+
+    /// Give up after restarting the command this many times in a session
+    #[arg(long, value_name = "COUNT", requires = "restart")]
+    pub restart_limit: Option<u32>,
+
+    /// What the client's socket does once the command exits: close, stay
+    /// open showing the final screen until the client leaves, or close with
+    /// code 1012 so the web page offers to start it again
+    #[arg(long, value_enum, default_value = "close")]
+    pub on_exit: OnExit,
+
+    /// Limit each session's output to this many bytes per second; 0 means
+    /// unlimited
+    #[arg(long, value_name = "BYTES_PER_SEC", default_value = "0")]
+    pub max_output_rate: u64,
+
+    /// Abort the command if it produces no output for SECONDS
+    #[arg(long, value_name = "SECONDS", value_parser = value_parser!(u64).range(1..))]
+    pub output_idle_timeout: Option<u64>,
+
+    /// XON/XOFF flow control on the terminal: with `software`, Ctrl-S pauses
+    /// output and Ctrl-Q resumes it; with `none` both reach the command.
+    /// Defaults to the system setting
+    #[cfg(unix)]
+    #[arg(long, value_enum)]
+    pub flow_control: Option<FlowControlArg>,
+
+    /// Don't make the terminal the command's controlling terminal. The
+    /// command still runs on a terminal, but Ctrl-C, Ctrl-Z etc. reach it as
+    /// plain input instead of signalling it, and shells can't use job control
+    #[cfg(unix)]
+    #[arg(long)]
+    pub no_controlling_terminal: bool,
+
+    /// Pass file descriptors rttyd inherited on to commands, instead of
+    /// giving them only the terminal
+    #[cfg(unix)]
+    #[arg(long)]
+    pub inherit_fds: bool,
+
+    /// Give up on input the command hasn't read within MS milliseconds,
+    /// instead of holding up the session until it does
+    #[cfg(unix)]
+    #[arg(long, value_name = "MS", value_parser = value_parser!(u64).range(1..))]
+    pub pty_write_timeout: Option<u64>,
+
+    /// What to do when --pty-write-timeout passes: drop the input or abort
+    /// the command
+    #[cfg(unix)]
+    #[arg(
+        long,
+        value_enum,
+        default_value = "drop",
+        requires = "pty_write_timeout"
+    )]
+    pub on_write_timeout: OnWriteTimeout,
+
+    /// Forward window title changes (OSC 0/2) to the client as `5;` messages
+    #[arg(long)]
+    pub detect_title: bool,
+
+    /// Send a `7;` message when the command rings the terminal bell
+    #[arg(long)]
+    pub detect_bell: bool,
+
+    /// Maximum bytes of output buffered for a client that can't keep up
+    #[arg(long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    pub output_limit: Option<usize>,
+
+    /// What to do when --output-limit is reached: drop the oldest output
+    /// (marked with "[output truncated]") or pause the command
+    #[arg(long, value_enum, default_value = "block", requires = "output_limit")]
+    pub overflow: Overflow,
+
+    /// Serve COMMAND on `/ws/NAME` in addition to the default command on
+    /// `/ws`; may be repeated. With --no-shell, COMMAND is split on whitespace
+    #[arg(long = "route", value_name = "NAME=COMMAND")]
+    pub routes: Vec<Route>,
+
+    /// Set an environment variable for commands; may be repeated
+    #[arg(long, value_name = "KEY=VALUE")]
+    pub env: Vec<EnvVar>,
+
+    /// Start commands with only PATH, TERM, HOME, --env-passthrough and
+    /// --env variables instead of rttyd's whole environment
+    #[arg(long)]
+    pub env_clear: bool,
+
+    /// Pass this variable from rttyd's environment on to commands despite
+    /// --env-clear; may be repeated
+    #[arg(long, value_name = "KEY", requires = "env_clear")]
+    pub env_passthrough: Vec<String>,
+
+    /// Run the shell script in this file (`-` for stdin) instead of COMMAND
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["command", "no_shell"])]
+    pub command_file: Option<PathBuf>,
+
+    /// Command to run: a single argument is a script for --shell, several are
+    /// a program and its arguments, executed without a shell so they are
+    /// passed verbatim (e.g. `rttyd -- top -d 1`)
+    #[arg(required_unless_present_any = ["config", "routes", "command_file"])]
+    pub command: Vec<String>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum Overflow {
+    Drop,
+    Block,
+}
+
+// tasks run instead of the server
+#[derive(clap::Subcommand, Debug)]
+pub enum RttydCommand {
+    /// Write the embedded web assets to DIR, e.g. as a starting point for a
+    /// customized frontend
+    DumpAssets {
+        dir: PathBuf,
+
+        /// Replace files that already exist in DIR
+        #[arg(long)]
+        overwrite: bool,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum FlowControlArg {
+    None,
+    Software,
+}
+
+impl From<FlowControlArg> for FlowControl {
+    fn from(flow_control: FlowControlArg) -> Self {
+        match flow_control {
+            FlowControlArg::None => Self::None,
+            FlowControlArg::Software => Self::Software,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OnExit {
+    Close,
+    Hold,
+    Reconnect,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum OnWriteTimeout {
+    Drop,
+    Abort,
+}
+
+impl From<OnWriteTimeout> for WriteTimeoutPolicy {
+    fn from(policy: OnWriteTimeout) -> Self {
+        match policy {
+            OnWriteTimeout::Drop => Self::Drop,
+            OnWriteTimeout::Abort => Self::Abort,
+        }
+    }
+}
+
+impl From<Overflow> for OverflowPolicy {
+    fn from(overflow: Overflow) -> Self {
+        match overflow {
+            Overflow::Drop => Self::Drop,
+            Overflow::Block => Self::Block,
+        }
+    }
+}
+
+impl RttydArgs {
+    /// Parses the command line and merges in the config file, if any.
+    fn load() -> Self {
+        let argv = std::env::args_os().collect::<Vec<_>>();
+        let mut matches = Self::command().get_matches_from(&argv);
+        let config = matches
+            .get_one::<PathBuf>("config")
+            .filter(|_| matches.subcommand().is_none())
+            .cloned();
+        if let Some(path) = config {
+            let argv = config::merge_config_file(argv, &matches, &path).unwrap_or_else(|err| {
+                eprintln!("error: {err}");
+                std::process::exit(2);
+            });
+            matches = Self::command().get_matches_from(argv);
+        }
+        let mut args = Self::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+        if args.subcommand.is_some() {
+            return args;
+        }
+        if let Err(err) = args.read_files() {
+            eprintln!("error: {err}");
+            std::process::exit(2);
+        }
+        if let Some(Err(err)) = args.init_input.as_deref().map(unescape) {
+            eprintln!("error: invalid --init-input: {err}");
+            std::process::exit(2);
+        }
+        if args.command.is_empty() && args.routes.is_empty() {
+            eprintln!("error: no command given on the command line or in the config file");
+            std::process::exit(2);
+        }
+        let commands = args
+            .command_spec()
+            .into_iter()
+            .chain(args.route_specs().into_values());
+        for command in commands {
+            if let Err(err) = command.check_program() {
+                eprintln!("error: {err}");
+                std::process::exit(2);
+            }
+        }
+        let mut names = std::collections::HashSet::new();
+        if let Some(route) = args.routes.iter().find(|route| !names.insert(&route.name)) {
+            eprintln!("error: route `{}` is defined more than once", route.name);
+            std::process::exit(2);
+        }
+        args
+    }
+
+    /// Reads the values given as files: --command-file (`-` for stdin), and
+    /// `@PATH` for --banner and --init-input.
+    fn read_files(&mut self) -> Result<(), String> {
+        if let Some(path) = &self.command_file {
+            let script = if path.as_os_str() == "-" {
+                std::io::read_to_string(std::io::stdin())
+            } else {
+                std::fs::read_to_string(path)
+            };
+            let script =
+                script.map_err(|err| format!("failed to read {}: {err}", path.display()))?;
+            self.command = vec![script];
+        }
+        if let Some(path) = self
+            .banner
+            .as_deref()
+            .and_then(|banner| banner.strip_prefix('@'))
+        {
+            let banner = std::fs::read_to_string(path)
+                .map_err(|err| format!("failed to read banner from {path}: {err}"))?;
+            self.banner = Some(banner);
+        }
+        if let Some(path) = self
+            .init_input
+            .as_deref()
+            .and_then(|input| input.strip_prefix('@'))
+        {
+            let input = std::fs::read_to_string(path)
+                .map_err(|err| format!("failed to read init input from {path}: {err}"))?;
+            self.init_input = Some(input);
+        }
+        Ok(())
+    }
+
+    fn command_options(&self) -> CommandOptions {
+        CommandOptions {
+            kill_grace: Duration::from_secs(self.kill_grace),
+            input_buffer: self.input_buffer,
+            detect_title: self.detect_title,
+            detect_bell: self.detect_bell,
+            output_limit: self.output_limit.map(|limit| (limit, self.overflow.into())),
+            #[cfg(unix)]
+            flow_control: self.flow_control.map(Into::into),
+            #[cfg(unix)]
+            controlling_terminal: !self.no_controlling_terminal,
+            #[cfg(unix)]
+            close_fds: !self.inherit_fds,
+            #[cfg(unix)]
+            write_timeout: self
+                .pty_write_timeout
+                .map(|ms| (Duration::from_millis(ms), self.on_write_timeout.into())),
+            ..Default::default()
+        }
+    }
+
+    /// Settings shared by every connection, logging sessions to `audit`.
+    fn app_state(&self, audit: Option<AuditLog>) -> AppState {
+        AppState {
+            command: self.command_spec(),
+            routes: self.route_specs(),
+            env: self.command_env(),
+            options: self.command_options(),
+            trust_proxy: self.trust_proxy,
+            rate_limiter: self.rate_limit.map(RateLimiter::new),
+            client_limiter: self.max_clients_per_ip.map(ClientLimiter::new),
+            max_message_bytes: self.max_message_bytes,
+            auth: (!self.auth_tokens.is_empty())
+                .then(|| TokenAuth::new(self.auth_tokens.clone(), self.one_time_tokens)),
+            title: self.title.clone(),
+            banner: self.banner.as_deref().map(terminal_text),
+            init_input: self
+                .init_input
+                .as_deref()
+                .map(|input| unescape(input).expect("checked when loading arguments")),
+            sessions: SessionRegistry::default(),
+            audit,
+            audit_redact: self.audit_redact,
+            detach: self
+                .detach_on_disconnect
+                .then(|| self.detach_timeout.map(Duration::from_secs)),
+            scrollback_bytes: self.scrollback_bytes,
+            shared: self.shared,
+            spa: self.spa,
+            once: self.once,
+            restart: self.restart.then_some(self.restart_limit),
+            on_exit: self.on_exit,
+            max_output_rate: self.max_output_rate,
+            output_idle_timeout: self.output_idle_timeout.map(Duration::from_secs),
+            status_interval: self.status_interval.map(Duration::from_secs),
+            keepalive: self.keepalive.map(Duration::from_secs),
+            session_started: Arc::default(),
+            shutdown: Arc::new(Notify::new()),
+            stopping: CancellationToken::new(),
+            live_sessions: watch::Sender::new(0),
+            exit_code: Arc::default(),
+        }
+    }
+
+    /// The server's routes; the access log, which must be opened first, is
+    /// layered on by the caller.
+    fn router(&self) -> Router<Arc<AppState>> {
+        let mut app = Router::new()
+            .route("/ws", get(handle_websocket))
+            .route("/ws/{route}", get(handle_websocket))
+            .route("/config", get(handle_config));
+        if self.enable_control_api {
+            app = app.route("/sessions/{id}/input", post(handle_session_input));
+        }
+        let mut app = app.fallback(get(static_handler));
+        if !self.http2_cleartext {
+            app = app.layer(middleware::from_fn(reject_http2));
+        }
+        app
+    }
+
+    /// The command served on `/ws`, if any.
+    fn command_spec(&self) -> Option<CommandSpec> {
+        (!self.command.is_empty()).then(|| self.spec_for(self.command.clone()))
+    }
+
+    /// The commands served on `/ws/<name>`, keyed by name.
+    fn route_specs(&self) -> HashMap<String, CommandSpec> {
+        self.routes
+            .iter()
+            .map(|route| {
+                let spec = if self.no_shell {
+                    CommandSpec::Argv(route.command.split_whitespace().map(String::from).collect())
+                } else {
+                    CommandSpec::Shell {
+                        shell: self.shell.clone(),
+                        script: route.command.clone(),
+                    }
+                };
+                (route.name.clone(), spec)
+            })
+            .collect()
+    }
+
+    /// The environment for commands, as set by `--env`, `--env-clear` and
+    /// `--env-passthrough`.
+    fn command_env(&self) -> CommandEnv {
+        let mut vars = Vec::new();
+        if self.env_clear {
+            let keys = BASE_ENV
+                .iter()
+                .copied()
+                .chain(self.env_passthrough.iter().map(String::as_str));
+            for key in keys {
+                if let Some(value) = std::env::var_os(key) {
+                    vars.push((key.into(), value));
+                }
+            }
+        }
+        vars.extend(
+            self.env
+                .iter()
+                .map(|var| (var.key.clone().into(), var.value.clone().into())),
+        );
+        CommandEnv {
+            clear: self.env_clear,
+            vars,
+        }
+    }
+
+    fn spec_for(&self, mut command: Vec<String>) -> CommandSpec {
+        if self.no_shell || command.len() > 1 {
+            CommandSpec::Argv(command)
+        } else {
+            CommandSpec::Shell {
+                shell: self.shell.clone(),
+                script: command.pop().unwrap_or_default(),
+            }
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() {
     // initialize tracing
-    let args = RttydArgs::parse();
+    let args = RttydArgs::load();
+    if let Some(RttydCommand::DumpAssets { dir, overwrite }) = &args.subcommand {
+        match dump_assets(dir, *overwrite) {
+            Ok(0) => {
+                eprintln!("error: this build has no web assets embedded");
+                std::process::exit(1);
+            }
+            Ok(count) => println!("Wrote {count} files to {}", dir.display()),
+            Err(err) => {
+                eprintln!("error: {err}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
     let level = match args.verbosity.as_str() {
         "trace" => Level::TRACE,
         "debug" => Level::DEBUG,
@@ -56,127 +694,2609 @@ async fn main() {
         .with_max_level(level)
         .with_ansi(false)
         .init();
-    // Build the Axum application
-    let app = Router::new()
-        .route(
-            "/ws",
-            get(move |ws: WebSocketUpgrade| handle_websocket(ws, args.command.clone())),
-        )
-        .fallback(get(static_handler));
+    let audit = match &args.audit_log {
+        Some(path) => Some(AuditLog::open(path).await.unwrap_or_else(|err| {
+            eprintln!("error: failed to open {}: {err}", path.display());
+            std::process::exit(1);
+        })),
+        None => None,
+    };
+    let state = Arc::new(args.app_state(audit));
+    let shutdown = state.shutdown.clone();
+    let stopping = state.stopping.clone();
+    let live_sessions = state.live_sessions.subscribe();
+    let exit_code = state.exit_code.clone();
+    let mut app = args.router();
+    if let Some(path) = &args.access_log {
+        let log = AccessLog::open(path, args.trust_proxy)
+            .await
+            .unwrap_or_else(|err| {
+                eprintln!("error: failed to open {}: {err}", path.display());
+                std::process::exit(1);
+            });
+        app = app.layer(middleware::from_fn_with_state(log, access_log::log_request));
+    }
+    let app = app.with_state(state);
     // Start the server
-    let listener = TcpListener::bind(format!("{}:{}", args.host, args.port))
+    #[cfg(unix)]
+    if let Some(path) = &args.unix_socket {
+        let listener = bind_unix_socket(path, args.unix_socket_mode).unwrap_or_else(|err| {
+            eprintln!("error: failed to bind {}: {err}", path.display());
+            std::process::exit(1);
+        });
+        let pid_file = create_pid_file(&args);
+        announce_listening(&format!("unix:{}", path.display()), args.json_startup);
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<PeerAddr>(),
+        )
+        .with_graceful_shutdown(shutdown_signal(shutdown, stopping))
         .await
         .unwrap();
-    println!("Listening on http://{}:{}", args.host, args.port);
-    axum::serve(listener, app).await.unwrap();
+        wait_for_sessions(live_sessions).await;
+        drop(pid_file);
+        exit_with_session_code(&exit_code);
+        return;
+    }
+    let listeners = bind_hosts(&args.host, args.port)
+        .await
+        .unwrap_or_else(|err| {
+            eprintln!("error: {err}");
+            std::process::exit(1);
+        });
+    let pid_file = create_pid_file(&args);
+    let stopped = shutdown_signal(shutdown, stopping).boxed().shared();
+    let servers = listeners
+        .into_iter()
+        .zip(&args.host)
+        .map(|(listener, host)| {
+            let url = match listener.local_addr() {
+                Ok(addr) => format!("http://{addr}"),
+                Err(_) => format!("http://{host}:{}", args.port),
+            };
+            announce_listening(&url, args.json_startup);
+            axum::serve(
+                listener,
+                app.clone()
+                    .into_make_service_with_connect_info::<PeerAddr>(),
+            )
+            .with_graceful_shutdown(stopped.clone())
+            .into_future()
+        });
+    futures_util::future::try_join_all(servers).await.unwrap();
+    wait_for_sessions(live_sessions).await;
+    drop(pid_file);
+    exit_with_session_code(&exit_code);
 }
 
-async fn handle_websocket(ws: WebSocketUpgrade, command: String) -> impl IntoResponse {
-    ws.on_upgrade(move |socket| handle_socket(socket, command))
+/// Binds `port` on every host before serving any, so a failure leaves none
+/// open.
+async fn bind_hosts(hosts: &[String], port: u16) -> Result<Vec<TcpListener>, String> {
+    let mut listeners = Vec::with_capacity(hosts.len());
+    for host in hosts {
+        let listener = TcpListener::bind((host.as_str(), port))
+            .await
+            .map_err(|err| format!("failed to bind {host} port {port}: {err}"))?;
+        listeners.push(listener);
+    }
+    Ok(listeners)
 }
 
-async fn handle_socket(socket: axum::extract::ws::WebSocket, command: String) {
-    let use_binary = true;
-    let (mut tx, mut rx) = socket.split();
-    let aborter = Arc::new(Notify::new());
-    let (mut command_tx, mut command_rx) = start_command(
-        Command::new("sh").arg("-c").arg(command),
-        aborter.clone(),
-        None,
-    )
-    .unwrap();
-    loop {
-        tokio::select! {
-            msg = rx.next() => {
-                if let Some(msg) = msg {
-                    match msg {
-                        Ok(msg) => {
-                            match msg {
-                                Message::Text(text) => {
-                                    let text = text.to_string();
-                                    if text.starts_with("0;") {
-                                        let data = base64::engine::general_purpose::STANDARD.decode(text[2..].as_bytes()).unwrap();
-                                        command_rx.send(CommandInputItem::Input(data)).await.unwrap();
-                                    } else if text.starts_with("1;") {
-                                        let data = text[2..].to_string();
-                                        command_rx.send(CommandInputItem::InputString(data)).await.unwrap();
-                                    } else if text.starts_with("2;") {
-                                        let split = text.split(";").collect::<Vec<&str>>();
-                                        let data = pty_process::Size::new(split[1].parse().unwrap(), split[2].parse().unwrap());
-                                        command_rx.send(CommandInputItem::Resize(data)).await.unwrap();
-                                    } else {
-                                        warn!("Received message: {}", text);
-                                    }
-                                }
-                                Message::Binary(data) => {
-                                    command_rx.send(CommandInputItem::Input(data.to_vec())).await.unwrap();
-                                }
-                                Message::Close(_) => {
-                                    aborter.notify_waiters();
-                                    break;
-                                }
-                                Message::Ping(data) => {
-                                    tx.send(Message::Pong(data)).await.unwrap();
-                                }
-                                Message::Pong(_) => (),
-                            }
-                        }
-                        Err(e) => {
-                            println!("Error: {}", e);
-                            aborter.notify_waiters();
-                            break;
-                        }
-                    }
-                } else {
-                    info!("Client closed, aborting command");
-                    aborter.notify_waiters();
-                    break;
-                }
+/// Tells whoever started rttyd that it is serving at `url`.
+fn announce_listening(url: &str, json: bool) {
+    println!("{}", listening_message(url, json));
+}
+
+/// The line announcing `url`: a JSON event for --json-startup, otherwise
+/// one for people.
+fn listening_message(url: &str, json: bool) -> String {
+    if json {
+        let event = serde_json::json!({
+            "event": "listening",
+            "url": url,
+            "version": env!("PKG_LONG_VERSION"),
+            "pid": std::process::id(),
+        });
+        event.to_string()
+    } else {
+        format!("Listening on {url}")
+    }
+}
+
+/// Resolves once the server should stop: on Ctrl-C, SIGTERM, or when
+/// `shutdown` is notified. Cancels `stopping` first, which ends every
+/// session.
+async fn shutdown_signal(shutdown: Arc<Notify>, stopping: CancellationToken) {
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{SignalKind, signal};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
             }
-            Some(output) = command_tx.next() => {
-                match output {
-                    CommandOutputItem::Output(output) => {
-                        if use_binary {
-                            tx.send(Message::Binary(output)).await.unwrap();
-                        } else {
-                            tx.send(Message::Text(format!("0;{}", base64::engine::general_purpose::STANDARD.encode(&output)).into())).await.unwrap();
-                        }
-                    }
-                    CommandOutputItem::Error(error) => {
-                        warn!("Error: {}", error);
-                    }
-                    CommandOutputItem::Exit(exit) => {
-                        tx.send(Message::Text(format!("1;{}", exit).into())).await.unwrap();
-                        break;
-                    }
-                }
+            Err(err) => {
+                warn!("Failed to listen for SIGTERM: {err}");
+                std::future::pending().await
             }
         }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        () = shutdown.notified() => {}
+        _ = tokio::signal::ctrl_c() => info!("Interrupted, shutting down"),
+        () = terminate => info!("Terminated, shutting down"),
     }
+    stopping.cancel();
 }
 
-async fn static_handler(uri: Uri) -> impl IntoResponse {
-    let mut path = PathBuf::from(uri.path().trim_start_matches("/"));
+/// Waits until every session has aborted its command and closed its socket.
+async fn wait_for_sessions(mut live_sessions: watch::Receiver<usize>) {
+    let live = *live_sessions.borrow();
+    if live > 0 {
+        info!(live, "Waiting for sessions to end");
+    }
+    // fails only once the state is gone, and with it every session
+    live_sessions.wait_for(|live| *live == 0).await.ok();
+}
 
-    if path.file_name() == None {
-        path = path.join("index.html");
+/// Claim on the one session `--once` serves. Unless kept once the client's
+/// socket is upgraded, dropping it lets another client start the session.
+struct OnceClaim(Option<Arc<AtomicBool>>);
+
+impl OnceClaim {
+    /// Claims the session, or returns `None` if another client has.
+    fn new(started: &Arc<AtomicBool>) -> Option<Self> {
+        (!started.swap(true, Ordering::SeqCst)).then(|| Self(Some(started.clone())))
     }
 
-    match Asset::get(path.to_str().unwrap()) {
-        Some(content) => {
-            let mime = mime_guess::from_path(path).first_or_octet_stream();
-            Response::builder()
-                .header(header::CONTENT_TYPE, mime.as_ref())
-                .body(String::from_utf8(content.data.to_vec()).unwrap())
-                .unwrap()
+    /// Keeps the session claimed for good.
+    fn keep(mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for OnceClaim {
+    fn drop(&mut self) {
+        if let Some(started) = self.0.take() {
+            started.store(false, Ordering::SeqCst);
         }
-        None => Response::builder()
-            .status(StatusCode::NOT_FOUND)
-            .body("Not Found".to_string())
-            .unwrap(),
     }
 }
 
-#[derive(Embed)]
-#[folder = "web/dist/"]
-struct Asset;
+/// Counts a session as live until dropped, so shutdown can wait for it.
+struct LiveSession(watch::Sender<usize>);
+
+impl LiveSession {
+    fn new(live_sessions: &watch::Sender<usize>) -> Self {
+        live_sessions.send_modify(|live| *live += 1);
+        Self(live_sessions.clone())
+    }
+}
+
+impl Drop for LiveSession {
+    fn drop(&mut self) {
+        self.0.send_modify(|live| *live -= 1);
+    }
+}
+
+/// Writes `--pid-file`, once every listener is bound so that a failed start
+/// leaves no file behind.
+fn create_pid_file(args: &RttydArgs) -> Option<PidFile> {
+    args.pid_file.as_deref().map(|path| {
+        PidFile::create(path).unwrap_or_else(|err| {
+            eprintln!("error: failed to write {}: {err}", path.display());
+            std::process::exit(1);
+        })
+    })
+}
+
+/// File holding rttyd's process id, removed when dropped.
+struct PidFile(PathBuf);
+
+impl PidFile {
+    fn create(path: &std::path::Path) -> std::io::Result<Self> {
+        std::fs::write(path, format!("{}\n", std::process::id()))?;
+        Ok(Self(path.to_owned()))
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_file(&self.0) {
+            warn!("Failed to remove {}: {err}", self.0.display());
+        }
+    }
+}
+
+/// Exits with the code recorded by the `--once` session, if any.
+fn exit_with_session_code(exit_code: &OnceLock<i32>) {
+    if let Some(code) = exit_code.get() {
+        std::process::exit(*code);
+    }
+}
+
+#[cfg(unix)]
+fn parse_mode(s: &str) -> Result<u32, String> {
+    u32::from_str_radix(s, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o777)
+        .ok_or_else(|| format!("invalid octal file mode `{s}`"))
+}
+
+/// Binds a Unix socket at `path`, replacing a stale socket left behind by a
+/// previous run.
+#[cfg(unix)]
+fn bind_unix_socket(
+    path: &std::path::Path,
+    mode: u32,
+) -> std::io::Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path)?,
+        Ok(_) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                "path exists and is not a socket",
+            ));
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err),
+    }
+    let listener = tokio::net::UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    Ok(listener)
+}
+
+/// Settings shared by every connection.
+struct AppState {
+    /// Command served on `/ws`.
+    command: Option<CommandSpec>,
+    /// Commands served on `/ws/<name>`.
+    routes: HashMap<String, CommandSpec>,
+    env: CommandEnv,
+    options: CommandOptions,
+    trust_proxy: bool,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    client_limiter: Option<ClientLimiter>,
+    max_message_bytes: usize,
+    auth: Option<TokenAuth>,
+    /// Name reported to the web page through `/config`.
+    title: Option<String>,
+    /// Written to each client before the command starts.
+    banner: Option<Bytes>,
+    /// Typed into each new command once it has started.
+    init_input: Option<Vec<u8>>,
+    sessions: SessionRegistry,
+    audit: Option<AuditLog>,
+    audit_redact: bool,
+    /// Keep commands running without a client, aborting them after the
+    /// given time if set.
+    detach: Option<Option<Duration>>,
+    scrollback_bytes: usize,
+    /// Let clients join running sessions as viewers.
+    shared: bool,
+    /// Serve index.html for page requests that match no asset.
+    spa: bool,
+    once: bool,
+    /// Restart exited commands, up to the given number of times if set.
+    restart: Option<Option<u32>>,
+    on_exit: OnExit,
+    /// Output bytes per second allowed per session, 0 for no limit.
+    max_output_rate: u64,
+    /// Abort commands that stay silent for this long.
+    output_idle_timeout: Option<Duration>,
+    status_interval: Option<Duration>,
+    keepalive: Option<Duration>,
+    /// Set once the `--once` session has been claimed.
+    session_started: Arc<AtomicBool>,
+    /// Notified to stop the server gracefully.
+    shutdown: Arc<Notify>,
+    /// Cancelled once the server stops, ending every session.
+    stopping: CancellationToken,
+    /// Number of sessions still running, which shutdown waits for.
+    live_sessions: watch::Sender<usize>,
+    /// Exit code for rttyd itself, set when the `--once` session ends.
+    exit_code: Arc<OnceLock<i32>>,
+}
+
+/// Exit code of `rttyd --once` when the command was aborted rather than
+/// exiting by itself.
+const ABORTED_EXIT_CODE: i32 = 125;
+
+/// Why a session's socket loop stopped.
+#[derive(Debug)]
+enum SessionEnd {
+    /// The client closed the WebSocket.
+    ClientClosed,
+    /// The WebSocket failed.
+    ClientError(String),
+    /// The command exited on its own with the given status code.
+    CommandExited(i32),
+    /// The command was aborted while the client was still connected.
+    Aborted,
+    /// No client reattached to the detached session in time.
+    DetachTimedOut,
+    /// The command produced no output for `--output-idle-timeout`.
+    OutputIdleTimedOut,
+    /// The command could not be started.
+    SpawnFailed(String),
+    /// rttyd is shutting down.
+    ServerStopped,
+}
+
+impl From<CommandExit> for SessionEnd {
+    fn from(exit: CommandExit) -> Self {
+        match exit {
+            CommandExit::Exited(code) => Self::CommandExited(code),
+            CommandExit::Aborted => Self::Aborted,
+        }
+    }
+}
+
+impl SessionEnd {
+    /// Whether the client went away while the command was running.
+    fn client_gone(&self) -> bool {
+        matches!(
+            self,
+            Self::ClientClosed | Self::ClientError(_) | Self::DetachTimedOut
+        )
+    }
+
+    /// Whether the command is still running and must be aborted.
+    fn must_abort(&self) -> bool {
+        self.client_gone() || matches!(self, Self::OutputIdleTimedOut | Self::ServerStopped)
+    }
+
+    /// Exit code rttyd reports for this session in `--once` mode.
+    fn exit_code(&self) -> i32 {
+        match self {
+            Self::CommandExited(code) => *code,
+            Self::ClientClosed
+            | Self::ClientError(_)
+            | Self::Aborted
+            | Self::DetachTimedOut
+            | Self::OutputIdleTimedOut
+            | Self::SpawnFailed(_)
+            | Self::ServerStopped => ABORTED_EXIT_CODE,
+        }
+    }
+
+    /// Whether the client's socket stays open after the session ended.
+    fn holds_socket(&self, on_exit: OnExit) -> bool {
+        matches!(self, Self::CommandExited(_)) && on_exit == OnExit::Hold
+    }
+
+    /// Close frame telling a still-connected client why the session ended.
+    /// A client that closed the socket itself is answered with the close
+    /// handshake's reply instead.
+    fn close_frame(&self, on_exit: OnExit) -> Option<CloseFrame> {
+        let (code, reason) = match self {
+            Self::ClientClosed | Self::DetachTimedOut => return None,
+            // the socket may still be open after e.g. an oversized message
+            Self::ClientError(_) => (close_code::ERROR, "Connection error"),
+            Self::CommandExited(_) => match on_exit {
+                OnExit::Close => (close_code::NORMAL, "Command exited"),
+                OnExit::Hold => return None,
+                // "service restart": the client may connect again
+                OnExit::Reconnect => (close_code::RESTART, "Command exited"),
+            },
+            Self::Aborted => (close_code::AWAY, "Command aborted"),
+            Self::OutputIdleTimedOut => (close_code::AWAY, "No output timeout"),
+            Self::SpawnFailed(_) => (close_code::ERROR, "Command failed to start"),
+            Self::ServerStopped => return Some(server_stopped_frame()),
+        };
+        Some(CloseFrame {
+            code,
+            reason: reason.into(),
+        })
+    }
+}
+
+/// Close frame sent to clients still connected when rttyd shuts down.
+fn server_stopped_frame() -> CloseFrame {
+    CloseFrame {
+        code: close_code::AWAY,
+        reason: "Server shutting down".into(),
+    }
+}
+
+/// Converts bare `\n` line endings to `\r\n`, as the terminal expects
+/// for text that doesn't pass through the PTY.
+fn terminal_text(text: &str) -> Bytes {
+    text.replace("\r\n", "\n").replace('\n', "\r\n").into()
+}
+
+/// Expands backslash escapes in `--init-input`.
+fn unescape(text: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        let byte = match chars.next() {
+            Some('n') => b'\n',
+            Some('r') => b'\r',
+            Some('t') => b'\t',
+            Some('e') => 0x1b,
+            Some('\\') => b'\\',
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                (hex.len() == 2 && hex.bytes().all(|b| b.is_ascii_hexdigit()))
+                    .then(|| u8::from_str_radix(&hex, 16).unwrap())
+                    .ok_or_else(|| {
+                        format!("`\\x` must be followed by two hex digits, got `{hex}`")
+                    })?
+            }
+            Some(other) => return Err(format!("unknown escape `\\{other}`")),
+            None => return Err("trailing backslash".to_string()),
+        };
+        bytes.push(byte);
+    }
+    Ok(bytes)
+}
+
+/// A text message from a client.
+#[derive(Debug, PartialEq)]
+enum ClientMessage {
+    /// `0;<base64>`: raw bytes for the command.
+    Input(Vec<u8>),
+    /// `1;<text>`: text for the command.
+    Text(String),
+    /// `2;<rows>;<cols>`: resize the terminal.
+    Resize(rtty::Size),
+    /// `4;`: end of input.
+    Eof,
+    /// `a;[<bytes>]`: set (if given) and report how much output is retained.
+    ScrollbackSize(Option<usize>),
+    /// `b;[<bytes>]`: replay the retained output, or its last `<bytes>`.
+    Replay(usize),
+}
+
+#[derive(Debug, thiserror::Error)]
+enum MalformedMessage {
+    #[error("malformed input: {0}")]
+    Input(#[from] base64::DecodeError),
+    #[error("malformed resize: {0}")]
+    Resize(String),
+    #[error("malformed scrollback size: {0}")]
+    ScrollbackSize(String),
+    #[error("malformed scrollback request: {0}")]
+    Replay(String),
+    #[error("unknown message: {0}")]
+    Unknown(String),
+}
+
+/// Decodes a text message from a client.
+///
+/// Typed text arrives as `1;` and raw bytes as binary frames; base64 (`0;`)
+/// costs a third more and is only for clients that must send non-UTF-8 bytes
+/// but can't send binary frames.
+fn parse_client_message(text: &str) -> Result<ClientMessage, MalformedMessage> {
+    // the prefixes are ASCII, so stripping them never splits a character
+    if let Some(data) = text.strip_prefix("0;") {
+        // bounded by --max-message-bytes, which caps the encoded message
+        let data = base64::engine::general_purpose::STANDARD.decode(data)?;
+        Ok(ClientMessage::Input(data))
+    } else if let Some(data) = text.strip_prefix("1;") {
+        Ok(ClientMessage::Text(data.to_string()))
+    } else if let Some(data) = text.strip_prefix("2;") {
+        let size = parse_size(data).ok_or_else(|| MalformedMessage::Resize(text.to_string()))?;
+        Ok(ClientMessage::Resize(size))
+    } else if text == "4;" {
+        Ok(ClientMessage::Eof)
+    } else if let Some(data) = text.strip_prefix("a;") {
+        let bytes = match data {
+            "" => None,
+            data => Some(
+                data.parse()
+                    .map_err(|_| MalformedMessage::ScrollbackSize(text.to_string()))?,
+            ),
+        };
+        Ok(ClientMessage::ScrollbackSize(bytes))
+    } else if let Some(data) = text.strip_prefix("b;") {
+        let bytes = match data {
+            "" => usize::MAX,
+            data => data
+                .parse()
+                .map_err(|_| MalformedMessage::Replay(text.to_string()))?,
+        };
+        Ok(ClientMessage::Replay(bytes))
+    } else {
+        Err(MalformedMessage::Unknown(text.to_string()))
+    }
+}
+
+/// Parses the `<rows>;<cols>` body of a `2;` resize message.
+fn parse_size(s: &str) -> Option<rtty::Size> {
+    let (rows, cols) = s.split_once(';')?;
+    Some(rtty::Size::new(rows.parse().ok()?, cols.parse().ok()?))
+}
+
+/// Written to the terminal before a `--restart`.
+const RESTARTING_NOTICE: &[u8] = b"\r\n[restarting]\r\n";
+
+/// Delay before the `attempt`th restart (counting from zero), doubling from
+/// 250ms up to 10s so a command that keeps crashing doesn't spin.
+fn restart_backoff(attempt: u32) -> Duration {
+    Duration::from_millis(250)
+        .saturating_mul(1 << attempt.min(6))
+        .min(Duration::from_secs(10))
+}
+
+/// Body of the periodic `6;` status message.
+#[derive(Serialize)]
+struct SessionStatus {
+    bytes_in: u64,
+    bytes_out: u64,
+    uptime_secs: u64,
+}
+
+/// Response header of a WebSocket upgrade carrying the new session's id,
+/// for use with the control API.
+const SESSION_ID_HEADER: &str = "x-session-id";
+
+/// Body returned for plain HTTP requests to a WebSocket endpoint.
+const NOT_A_WEBSOCKET: &str = "This is a WebSocket endpoint. Open the rttyd page in a browser, \
+or connect with a WebSocket client, e.g. `websocat ws://<host>/ws`.\n";
+
+#[derive(Deserialize)]
+struct WsQuery {
+    token: Option<String>,
+    /// Id of a detached session to reattach to.
+    session: Option<String>,
+}
+
+async fn handle_websocket(
+    ws: Result<WebSocketUpgrade, WebSocketUpgradeRejection>,
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<PeerAddr>,
+    route: Option<Path<String>>,
+    headers: HeaderMap,
+    Query(query): Query<WsQuery>,
+) -> impl IntoResponse {
+    let route = route.map(|Path(route)| route);
+    let command = match &route {
+        Some(name) => state.routes.get(name),
+        None => state.command.as_ref(),
+    };
+    let Some(command) = command.cloned() else {
+        return (StatusCode::NOT_FOUND, "Not Found").into_response();
+    };
+    let ws = match ws {
+        Ok(ws) => ws,
+        // most likely a browser pointed at the endpoint
+        Err(
+            WebSocketUpgradeRejection::InvalidConnectionHeader(_)
+            | WebSocketUpgradeRejection::InvalidUpgradeHeader(_),
+        ) => {
+            return (
+                StatusCode::UPGRADE_REQUIRED,
+                [(header::UPGRADE, "websocket")],
+                NOT_A_WEBSOCKET,
+            )
+                .into_response();
+        }
+        Err(rejection) => return rejection.into_response(),
+    };
+    let session_id = Uuid::new_v4();
+    let client = peer::client_ip(peer, &headers, state.trust_proxy);
+    if let Some(limiter) = &state.rate_limiter
+        && !limiter.check(client)
+    {
+        warn!(%client, "Rate limit exceeded, rejecting connection");
+        return (StatusCode::TOO_MANY_REQUESTS, "Too Many Requests").into_response();
+    }
+    if let Some(auth) = &state.auth
+        && !auth.check(query.token.as_deref())
+    {
+        warn!(%client, "Invalid or missing token, rejecting connection");
+        return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
+    }
+    if let Some(id) = &query.session {
+        return reattach(ws, &state, id, client, query.token.as_deref()).await;
+    }
+    let slot = match &state.client_limiter {
+        Some(limiter) => match limiter.acquire(client) {
+            Some(slot) => Some(slot),
+            None => {
+                warn!(%client, "Too many sessions from client, rejecting connection");
+                return (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "Too many sessions from this address",
+                )
+                    .into_response();
+            }
+        },
+        None => None,
+    };
+    let once = if state.once {
+        let Some(claim) = OnceClaim::new(&state.session_started) else {
+            warn!(%client, "Already serving the --once session, rejecting connection");
+            return (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Session already in progress",
+            )
+                .into_response();
+        };
+        Some(claim)
+    } else {
+        None
+    };
+    if let Some(auth) = &state.auth
+        && !auth.consume(query.token.as_deref())
+    {
+        // a one-time token used up by another connection since it was
+        // checked; dropping the claim lets another client start the session
+        warn!(%client, "Token already used, rejecting connection");
+        return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
+    }
+    let span = session_span(session_id, client, route.as_deref());
+    span.in_scope(|| log_connected(peer, &headers));
+    let live = LiveSession::new(&state.live_sessions);
+    let mut response = ws
+        .max_message_size(state.max_message_bytes)
+        .max_frame_size(state.max_message_bytes)
+        .on_upgrade(move |socket| {
+            async move {
+                // hold the slot for as long as the session lasts
+                let _slot = slot;
+                let _live = live;
+                if let Some(once) = once {
+                    once.keep();
+                }
+                handle_socket(socket, state, command, session_id).await;
+            }
+            .instrument(span)
+        })
+        .into_response();
+    response.headers_mut().insert(
+        SESSION_ID_HEADER,
+        HeaderValue::from_str(&session_id.to_string()).unwrap(),
+    );
+    response
+}
+
+/// Hands a new client's socket to the detached session `id`, or with
+/// --shared to an attached one as a viewer.
+async fn reattach(
+    ws: WebSocketUpgrade,
+    state: &AppState,
+    id: &str,
+    client: std::net::IpAddr,
+    token: Option<&str>,
+) -> axum::response::Response {
+    let Ok(session) = Uuid::parse_str(id) else {
+        return (StatusCode::NOT_FOUND, "No such session").into_response();
+    };
+    let (socket_tx, claim) = match state.sessions.attach(session) {
+        Ok((attach, claim)) => {
+            info!(%client, session = id, "Client reattaching");
+            (attach, Some(claim))
+        }
+        Err(AttachError::Unknown) => {
+            return (StatusCode::NOT_FOUND, "No such session").into_response();
+        }
+        Err(AttachError::Attached) if state.shared => {
+            let Some(view) = state.sessions.view(session) else {
+                return (StatusCode::NOT_FOUND, "No such session").into_response();
+            };
+            info!(%client, session = id, "Viewer joining");
+            (view, None)
+        }
+        Err(AttachError::Attached) => {
+            warn!(%client, session = id, "Session has a client, rejecting reattach");
+            return (
+                StatusCode::CONFLICT,
+                "Session is attached to another client",
+            )
+                .into_response();
+        }
+    };
+    if let Some(auth) = &state.auth
+        && !auth.consume(token)
+    {
+        // a one-time token used up by another connection since it was
+        // checked; dropping the claim lets another client reattach
+        warn!(%client, session = id, "Token already used, rejecting reattach");
+        return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
+    }
+    ws.max_message_size(state.max_message_bytes)
+        .max_frame_size(state.max_message_bytes)
+        .on_upgrade(move |socket| async move {
+            // fails only if the session ended meanwhile, which closes the
+            // socket; if the upgrade fails instead, the claim is dropped
+            if socket_tx.send(socket).await.is_ok()
+                && let Some(claim) = claim
+            {
+                claim.keep();
+            }
+        })
+        .into_response()
+}
+
+/// Turns away HTTP/2 requests. Once built with HTTP/2 support the server
+/// speaks h2c to any client that opens with the HTTP/2 preface, so without
+/// --http2-cleartext the requests themselves are refused.
+async fn reject_http2(request: axum::extract::Request, next: middleware::Next) -> Response<Body> {
+    if request.version() == axum::http::Version::HTTP_2 {
+        return (
+            StatusCode::HTTP_VERSION_NOT_SUPPORTED,
+            "HTTP/2 is not enabled (see --http2-cleartext)",
+        )
+            .into_response();
+    }
+    next.run(request).await
+}
+
+/// Settings the web page reads on load.
+#[derive(Serialize)]
+struct ClientConfig {
+    title: Option<String>,
+    version: &'static str,
+    on_exit: OnExit,
+}
+
+async fn handle_config(State(state): State<Arc<AppState>>) -> Json<ClientConfig> {
+    Json(ClientConfig {
+        title: state.title.clone(),
+        version: env!("PKG_LONG_VERSION"),
+        on_exit: state.on_exit,
+    })
+}
+
+/// Writes the request body to the terminal of a live session.
+async fn handle_session_input(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> StatusCode {
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if !state.auth.as_ref().is_some_and(|auth| auth.check(token)) {
+        warn!("Invalid or missing token, rejecting control API request");
+        return StatusCode::UNAUTHORIZED;
+    }
+    let Some(input) = Uuid::parse_str(&id)
+        .ok()
+        .and_then(|id| state.sessions.input(id))
+    else {
+        return StatusCode::NOT_FOUND;
+    };
+    match input.send(body.to_vec()).await {
+        Ok(()) => StatusCode::OK,
+        // the session ended after we looked it up
+        Err(_) => StatusCode::NOT_FOUND,
+    }
+}
+
+async fn handle_socket(
+    socket: axum::extract::ws::WebSocket,
+    state: Arc<AppState>,
+    command: CommandSpec,
+    session_id: Uuid,
+) {
+    let use_binary = true;
+    let mut socket = socket;
+    if state.detach.is_some() || state.shared {
+        // browsers can't read the upgrade response's x-session-id header
+        let msg = Message::Text(format!("c;{session_id}").into());
+        socket.send(msg).await.ok();
+    }
+    if let Some(banner) = &state.banner {
+        // a client that's already gone is noticed once the session loop reads from it
+        socket.send(Message::Binary(banner.clone())).await.ok();
+    }
+    let aborter = Arc::new(Notify::new());
+    let mut options = state.options.clone();
+    let (mut command_tx, mut command_rx) = match start_command(
+        command.to_command(&state.env),
+        aborter.clone(),
+        options.clone(),
+    ) {
+        Ok(command) => command,
+        Err(err) => {
+            let end = SessionEnd::SpawnFailed(err.to_string());
+            warn!("Failed to start command: {err}");
+            socket.send(error_message(&err.to_string())).await.ok();
+            if let Some(frame) = end.close_frame(state.on_exit) {
+                socket.send(Message::Close(Some(frame))).await.ok();
+            }
+            shut_down_after_once(&state, &end);
+            return;
+        }
+    };
+    info!("Session started");
+    if let Some(input) = &state.init_input {
+        send_input(&mut command_rx, CommandInputItem::Input(input.clone())).await;
+    }
+    let mut restarts = 0u32;
+    // when to start the command again after it exited under --restart
+    let mut restart_at = None;
+    let mut registration = state
+        .sessions
+        .register(session_id, state.options.input_buffer);
+    let mut scrollback = Scrollback::new(state.scrollback_bytes);
+    let viewers = state.shared.then(|| broadcast::channel(VIEWER_BUFFER).0);
+    let mut throttle =
+        (state.max_output_rate > 0).then(|| OutputThrottle::new(state.max_output_rate));
+    // output read while throttled, sent once the throttle allows it
+    let mut held_output = None;
+    let mut audit = state
+        .audit
+        .clone()
+        .map(|log| SessionAudit::new(log, session_id, state.audit_redact));
+    let started = Instant::now();
+    let mut bytes_in = 0u64;
+    let mut bytes_out = 0u64;
+    let mut status_ticker = state
+        .status_interval
+        .map(|period| tokio::time::interval_at(tokio::time::Instant::now() + period, period));
+    let mut keepalive_ticker = state
+        .keepalive
+        .map(|period| tokio::time::interval_at(tokio::time::Instant::now() + period, period));
+    let mut output_deadline = state
+        .output_idle_timeout
+        .map(|timeout| tokio::time::Instant::now() + timeout);
+    let end = loop {
+        let (mut tx, mut rx) = socket.split();
+        let end = loop {
+            let throttled_until = throttle.as_ref().and_then(OutputThrottle::ready_at);
+            tokio::select! {
+                msg = rx.next() => {
+                    if let Some(msg) = msg {
+                        match msg {
+                            Ok(msg) => {
+                                match msg {
+                                    Message::Text(text) => {
+                                        bytes_in += text.len() as u64;
+                                        match parse_client_message(&text) {
+                                            Ok(ClientMessage::Input(data)) => {
+                                                if let Some(audit) = &mut audit {
+                                                    audit.input(&data);
+                                                }
+                                                send_input(&mut command_rx, CommandInputItem::Input(data)).await;
+                                            }
+                                            Ok(ClientMessage::Text(data)) => {
+                                                if let Some(audit) = &mut audit {
+                                                    audit.input(data.as_bytes());
+                                                }
+                                                send_input(&mut command_rx, CommandInputItem::InputString(data)).await;
+                                            }
+                                            Ok(ClientMessage::Resize(size)) => {
+                                                send_input(&mut command_rx, CommandInputItem::Resize(size)).await;
+                                            }
+                                            Ok(ClientMessage::Eof) => {
+                                                send_input(&mut command_rx, CommandInputItem::Eof).await;
+                                            }
+                                            Ok(ClientMessage::ScrollbackSize(bytes)) => {
+                                                if let Some(bytes) = bytes {
+                                                    scrollback.set_capacity(bytes.min(state.scrollback_bytes));
+                                                }
+                                                if let Err(err) = tx.send(Message::Text(format!("a;{}", scrollback.capacity()).into())).await {
+                                                    break SessionEnd::ClientError(err.to_string());
+                                                }
+                                            }
+                                            Ok(ClientMessage::Replay(bytes)) => {
+                                                let replay = base64::engine::general_purpose::STANDARD.encode(scrollback.tail(bytes));
+                                                if let Err(err) = tx.send(Message::Text(format!("b;{replay}").into())).await {
+                                                    break SessionEnd::ClientError(err.to_string());
+                                                }
+                                            }
+                                            Err(err) => warn!("Dropping {err}"),
+                                        }
+                                    }
+                                    Message::Binary(data) => {
+                                        bytes_in += data.len() as u64;
+                                        if let Some(audit) = &mut audit {
+                                            audit.input(&data);
+                                        }
+                                        send_input(&mut command_rx, CommandInputItem::Input(data.to_vec())).await;
+                                    }
+                                    Message::Close(_) => {
+                                        // tungstenite only queues the reply to a close; send it
+                                        tx.close().await.ok();
+                                        break SessionEnd::ClientClosed;
+                                    }
+                                    Message::Ping(data) => {
+                                        if let Err(err) = tx.send(Message::Pong(data)).await {
+                                            break SessionEnd::ClientError(err.to_string());
+                                        }
+                                    }
+                                    Message::Pong(_) => (),
+                                }
+                            }
+                            Err(err) => break SessionEnd::ClientError(err.to_string()),
+                        }
+                    } else {
+                        break SessionEnd::ClientClosed;
+                    }
+                }
+                Some(data) = registration.input.recv() => {
+                    if let Some(audit) = &mut audit {
+                        audit.input(&data);
+                    }
+                    send_input(&mut command_rx, CommandInputItem::Input(data)).await;
+                }
+                Some(viewer) = registration.view.recv() => {
+                    add_viewer(viewer, viewers.as_ref(), &scrollback, &state.stopping);
+                }
+                () = state.stopping.cancelled() => break SessionEnd::ServerStopped,
+                _ = tokio::time::sleep_until(throttled_until.unwrap_or_else(tokio::time::Instant::now)),
+                    if throttled_until.is_some() => {
+                    // output held back by the throttle doesn't count as silence
+                    if let Some(timeout) = state.output_idle_timeout {
+                        output_deadline = Some(tokio::time::Instant::now() + timeout);
+                    }
+                }
+                _ = tokio::time::sleep_until(output_deadline.unwrap_or_else(tokio::time::Instant::now)),
+                    if output_deadline.is_some() && throttled_until.is_none() => break SessionEnd::OutputIdleTimedOut,
+                _ = tokio::time::sleep_until(restart_at.unwrap_or_else(tokio::time::Instant::now)),
+                    if restart_at.is_some() => {
+                    restart_at = None;
+                    match start_command(command.to_command(&state.env), aborter.clone(), options.clone()) {
+                        Ok(command) => (command_tx, command_rx) = command,
+                        Err(err) => {
+                            let msg = error_message(&err.to_string());
+                            share(viewers.as_ref(), &msg);
+                            tx.send(msg).await.ok();
+                            break SessionEnd::SpawnFailed(err.to_string());
+                        }
+                    }
+                }
+                Some(output) = async {
+                    match held_output.take() {
+                        Some(output) => Some(CommandOutputItem::Output(output)),
+                        None => command_tx.next().await,
+                    }
+                }, if (held_output.is_none() || throttled_until.is_none()) && restart_at.is_none() => {
+                    match output {
+                        // only output is throttled; other items pass while it is held back
+                        CommandOutputItem::Output(output) if throttled_until.is_some() => {
+                            held_output = Some(output);
+                        }
+                        CommandOutputItem::Output(output) => {
+                            if let Some(timeout) = state.output_idle_timeout {
+                                output_deadline = Some(tokio::time::Instant::now() + timeout);
+                            }
+                            if let Some(throttle) = &mut throttle {
+                                throttle.consume(output.len());
+                            }
+                            bytes_out += output.len() as u64;
+                            if let Some(audit) = &mut audit {
+                                audit.output(&output);
+                            }
+                            scrollback.push(&output);
+                            let msg = if use_binary {
+                                Message::Binary(output)
+                            } else {
+                                Message::Text(format!("0;{}", base64::engine::general_purpose::STANDARD.encode(&output)).into())
+                            };
+                            share(viewers.as_ref(), &msg);
+                            if let Err(err) = tx.send(msg).await {
+                                break SessionEnd::ClientError(err.to_string());
+                            }
+                        }
+                        CommandOutputItem::Error(error) => {
+                            warn!("Error: {}", error);
+                            let msg = error_message(&error);
+                            share(viewers.as_ref(), &msg);
+                            if let Err(err) = tx.send(msg).await {
+                                break SessionEnd::ClientError(err.to_string());
+                            }
+                        }
+                        CommandOutputItem::Title(title) => {
+                            let msg = Message::Text(format!("5;{}", title).into());
+                            share(viewers.as_ref(), &msg);
+                            if let Err(err) = tx.send(msg).await {
+                                break SessionEnd::ClientError(err.to_string());
+                            }
+                        }
+                        CommandOutputItem::Resized(size) => {
+                            options.size = Some(size);
+                            let msg = Message::Text(format!("8;{};{}", size.cols, size.rows).into());
+                            share(viewers.as_ref(), &msg);
+                            if let Err(err) = tx.send(msg).await {
+                                break SessionEnd::ClientError(err.to_string());
+                            }
+                        }
+                        CommandOutputItem::Bell => {
+                            let msg = Message::Text("7;".into());
+                            share(viewers.as_ref(), &msg);
+                            if let Err(err) = tx.send(msg).await {
+                                break SessionEnd::ClientError(err.to_string());
+                            }
+                        }
+                        CommandOutputItem::Exit(CommandExit::Exited(code))
+                            if state.restart.is_some_and(|limit| limit.is_none_or(|limit| restarts < limit)) =>
+                        {
+                            info!(code, restarts, "Command exited, restarting");
+                            let msg = Message::Binary(RESTARTING_NOTICE.into());
+                            share(viewers.as_ref(), &msg);
+                            restart_at = Some(tokio::time::Instant::now() + restart_backoff(restarts));
+                            restarts += 1;
+                            if let Err(err) = tx.send(msg).await {
+                                break SessionEnd::ClientError(err.to_string());
+                            }
+                        }
+                        CommandOutputItem::Exit(exit) => {
+                            // the session is over either way; a client that's gone just misses the reason
+                            let msg = Message::Text(format!("1;{}", exit).into());
+                            share(viewers.as_ref(), &msg);
+                            tx.send(msg).await.ok();
+                            break exit.into();
+                        }
+                    }
+                }
+                _ = async { status_ticker.as_mut().unwrap().tick().await }, if status_ticker.is_some() => {
+                    let status = SessionStatus {
+                        bytes_in,
+                        bytes_out,
+                        uptime_secs: started.elapsed().as_secs(),
+                    };
+                    let status = serde_json::to_string(&status).unwrap();
+                    if let Err(err) = tx.send(Message::Text(format!("6;{status}").into())).await {
+                        break SessionEnd::ClientError(err.to_string());
+                    }
+                }
+                _ = async { keepalive_ticker.as_mut().unwrap().tick().await }, if keepalive_ticker.is_some() => {
+                    if let Err(err) = tx.send(Message::Ping(Bytes::new())).await {
+                        break SessionEnd::ClientError(err.to_string());
+                    }
+                }
+            }
+        };
+        if let Some(output) = held_output.take() {
+            // replayed to a client that reattaches
+            scrollback.push(&output);
+        }
+        if let Some(frame) = end.close_frame(state.on_exit) {
+            tx.send(Message::Close(Some(frame))).await.ok();
+        } else if end.holds_socket(state.on_exit) {
+            info!("Command exited, holding the connection open");
+            hold_open(&mut tx, &mut rx, &state.stopping).await;
+        }
+        if state.detach.is_some() && end.client_gone() {
+            match &end {
+                SessionEnd::ClientError(err) => warn!("Client error, detaching: {err}"),
+                _ => info!("Client closed, detaching"),
+            }
+            // a restart still waiting out its backoff happens now, leaving a command to park
+            if restart_at.take().is_some() {
+                match start_command(
+                    command.to_command(&state.env),
+                    aborter.clone(),
+                    options.clone(),
+                ) {
+                    Ok(command) => (command_tx, command_rx) = command,
+                    Err(err) => break SessionEnd::SpawnFailed(err.to_string()),
+                }
+            }
+            let parked = park(
+                &state,
+                &mut registration,
+                &mut command_tx,
+                &mut command_rx,
+                &mut scrollback,
+                viewers.as_ref(),
+                audit.as_mut(),
+            )
+            .await;
+            match parked {
+                Parked::Reattached(new_socket) => {
+                    info!("Client reattached");
+                    socket = *new_socket;
+                    let replay = scrollback.contents();
+                    socket.send(Message::Binary(replay)).await.ok();
+                    continue;
+                }
+                Parked::Ended(end) => break end,
+            }
+        }
+        break end;
+    };
+    log_session_end(&end);
+    if end.must_abort() {
+        aborter.notify_one();
+    }
+    if !end.holds_socket(state.on_exit) {
+        let frame = end.close_frame(state.on_exit).unwrap_or(CloseFrame {
+            code: close_code::AWAY,
+            reason: "Session ended".into(),
+        });
+        share(viewers.as_ref(), &Message::Close(Some(frame)));
+    }
+    // drive the command stream so the abort can run its grace period
+    while command_tx.next().await.is_some() {}
+    info!("Session ended");
+    shut_down_after_once(&state, &end);
+}
+
+/// The span every log line of a session is recorded in, so concurrent
+/// sessions can be told apart by `id`.
+fn session_span(session_id: Uuid, client: std::net::IpAddr, route: Option<&str>) -> Span {
+    info_span!("session", id = %session_id, %client, route)
+}
+
+fn log_connected(peer: PeerAddr, headers: &HeaderMap) {
+    let user_agent = headers
+        .get(header::USER_AGENT)
+        .and_then(|value| value.to_str().ok());
+    info!(
+        %peer,
+        forwarded_for = peer::forwarded_for(headers),
+        user_agent,
+        "Client connected"
+    );
+}
+
+fn log_session_end(end: &SessionEnd) {
+    match end {
+        SessionEnd::ClientClosed => info!("Client closed, aborting command"),
+        SessionEnd::ClientError(err) => warn!("Client error, aborting command: {err}"),
+        SessionEnd::CommandExited(code) => info!(code, "Command exited"),
+        SessionEnd::Aborted => warn!("Command aborted"),
+        SessionEnd::DetachTimedOut => warn!("No client reattached in time, aborting command"),
+        SessionEnd::OutputIdleTimedOut => warn!("No output timeout, aborting command"),
+        SessionEnd::SpawnFailed(err) => warn!("Failed to start command: {err}"),
+        SessionEnd::ServerStopped => info!("Server shutting down, aborting command"),
+    }
+}
+
+/// The `9;` message telling the client the command was lost, e.g. because
+/// its terminal couldn't be read or it couldn't be started.
+fn error_message(error: &str) -> Message {
+    Message::Text(format!("9;{error}").into())
+}
+
+/// Stops the server once the only session has ended under `--once`.
+fn shut_down_after_once(state: &AppState, end: &SessionEnd) {
+    if state.once {
+        info!("Shutting down after the only session (--once)");
+        state.exit_code.set(end.exit_code()).ok();
+        state.shutdown.notify_one();
+    }
+}
+
+/// Passes `item` on to the command. The command's input closes as it exits,
+/// possibly before the exit is read from its output (e.g. with
+/// --output-limit reading ahead), so input arriving then is dropped and the
+/// session ends once the exit comes through.
+async fn send_input(input: &mut CommandInputSink, item: CommandInputItem) {
+    if input.send(item).await.is_err() {
+        warn!("Command input closed, dropping input");
+    }
+}
+
+/// Waits for the client to close a socket there is nothing more to send on,
+/// then completes the close handshake. Closes it first if rttyd stops.
+async fn hold_open(
+    tx: &mut SplitSink<axum::extract::ws::WebSocket, Message>,
+    rx: &mut SplitStream<axum::extract::ws::WebSocket>,
+    stopping: &CancellationToken,
+) {
+    loop {
+        tokio::select! {
+            msg = rx.next() => match msg {
+                Some(Ok(Message::Close(_))) => {
+                    // tungstenite only queues the reply to a close; send it
+                    tx.close().await.ok();
+                    break;
+                }
+                Some(Ok(_)) => {}
+                Some(Err(_)) | None => break,
+            },
+            () = stopping.cancelled() => {
+                tx.send(Message::Close(Some(server_stopped_frame()))).await.ok();
+                break;
+            }
+        }
+    }
+}
+
+/// How a detached session's wait for a client ended.
+enum Parked {
+    Reattached(Box<axum::extract::ws::WebSocket>),
+    Ended(SessionEnd),
+}
+
+/// Keeps a session without a client running, recording its output, until a
+/// client reattaches, the command exits, the --detach-timeout passes or rttyd
+/// stops.
+async fn park(
+    state: &AppState,
+    registration: &mut Registration,
+    output: &mut CommandOutputStream,
+    input: &mut CommandInputSink,
+    scrollback: &mut Scrollback,
+    viewers: Option<&broadcast::Sender<Message>>,
+    audit: Option<&mut SessionAudit>,
+) -> Parked {
+    let stopping = &state.stopping;
+    let timeout = state.detach.flatten();
+    let mut audit = audit;
+    registration.set_detached(true);
+    let deadline = timeout.map(|timeout| tokio::time::Instant::now() + timeout);
+    let parked = loop {
+        tokio::select! {
+            Some(socket) = registration.attach.recv() => break Parked::Reattached(Box::new(socket)),
+            item = output.next() => match item {
+                Some(CommandOutputItem::Output(data)) => {
+                    if let Some(audit) = &mut audit {
+                        audit.output(&data);
+                    }
+                    scrollback.push(&data);
+                    share(viewers, &Message::Binary(data));
+                }
+                Some(CommandOutputItem::Exit(exit)) => break Parked::Ended(exit.into()),
+                Some(_) => {}
+                None => break Parked::Ended(SessionEnd::Aborted),
+            },
+            Some(data) = registration.input.recv() => {
+                if let Some(audit) = &mut audit {
+                    audit.input(&data);
+                }
+                send_input(input, CommandInputItem::Input(data)).await;
+            }
+            Some(viewer) = registration.view.recv() => add_viewer(viewer, viewers, scrollback, stopping),
+            _ = tokio::time::sleep_until(deadline.unwrap_or_else(tokio::time::Instant::now)),
+                if deadline.is_some() => break Parked::Ended(SessionEnd::DetachTimedOut),
+            () = stopping.cancelled() => break Parked::Ended(SessionEnd::ServerStopped),
+        }
+    };
+    registration.set_detached(false);
+    parked
+}
+
+/// Messages a viewer of a shared session may fall behind by before it is
+/// disconnected.
+const VIEWER_BUFFER: usize = 1024;
+
+/// Passes a message meant for a shared session's client on to its viewers.
+fn share(viewers: Option<&broadcast::Sender<Message>>, msg: &Message) {
+    if let Some(viewers) = viewers {
+        // fails only when nobody is watching
+        viewers.send(msg.clone()).ok();
+    }
+}
+
+/// Starts streaming a shared session to a new viewer. The replay and the
+/// subscription are taken together, so the viewer sees every byte once.
+fn add_viewer(
+    socket: axum::extract::ws::WebSocket,
+    viewers: Option<&broadcast::Sender<Message>>,
+    scrollback: &Scrollback,
+    stopping: &CancellationToken,
+) {
+    let Some(viewers) = viewers else {
+        return;
+    };
+    info!("Viewer joined");
+    let viewer = serve_viewer(
+        socket,
+        scrollback.contents(),
+        viewers.subscribe(),
+        stopping.clone(),
+    );
+    tokio::spawn(viewer.in_current_span());
+}
+
+/// Sends a viewer the replay, then everything the session's client is sent,
+/// ignoring anything the viewer types.
+async fn serve_viewer(
+    socket: axum::extract::ws::WebSocket,
+    replay: Bytes,
+    mut messages: broadcast::Receiver<Message>,
+    stopping: CancellationToken,
+) {
+    let (mut tx, mut rx) = socket.split();
+    if tx.send(Message::Binary(replay)).await.is_err() {
+        info!("Viewer left");
+        return;
+    }
+    loop {
+        tokio::select! {
+            msg = messages.recv() => match msg {
+                Ok(msg) => {
+                    let last = matches!(msg, Message::Close(_));
+                    if tx.send(msg).await.is_err() || last {
+                        break;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    warn!(skipped, "Viewer fell behind, disconnecting it");
+                    let frame = CloseFrame {
+                        code: close_code::AGAIN,
+                        reason: "Viewer fell behind".into(),
+                    };
+                    tx.send(Message::Close(Some(frame))).await.ok();
+                    break;
+                }
+                // the session ended without closing, as with --on-exit hold
+                Err(broadcast::error::RecvError::Closed) => {
+                    hold_open(&mut tx, &mut rx, &stopping).await;
+                    break;
+                }
+            },
+            msg = rx.next() => match msg {
+                Some(Ok(Message::Close(_))) => {
+                    tx.close().await.ok();
+                    break;
+                }
+                Some(Err(_)) | None => break,
+                // viewers are read-only
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+    info!("Viewer left");
+}
+
+async fn static_handler(
+    State(state): State<Arc<AppState>>,
+    uri: Uri,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let path = asset_path(uri.path(), state.spa && accepts_html(&headers), |name| {
+        Asset::get(name).is_some()
+    });
+    asset_response(&path, &headers, |name| {
+        Asset::get(name).map(|asset| asset.data)
+    })
+}
+
+/// Answers a request for the asset at `path`, looked up with `get`.
+fn asset_response(
+    path: &std::path::Path,
+    headers: &HeaderMap,
+    get: impl Fn(&str) -> Option<Cow<'static, [u8]>>,
+) -> Response<Body> {
+    let name = path.to_str().unwrap();
+    match get(name) {
+        Some(content) => {
+            let mime = mime_guess::from_path(path).first_or_octet_stream();
+            let response = Response::builder()
+                .header(header::CONTENT_TYPE, mime.as_ref())
+                .header(header::VARY, "accept-encoding");
+            // prefer a pre-compressed sibling (`app.js.br`) the client accepts
+            let compressed = PRECOMPRESSED
+                .iter()
+                .filter(|(encoding, _)| accepts_encoding(headers, encoding))
+                .find_map(|(encoding, ext)| {
+                    get(&format!("{name}.{ext}")).map(|content| (encoding, content))
+                });
+            match compressed {
+                Some((encoding, compressed)) => response
+                    .header(header::CONTENT_ENCODING, *encoding)
+                    .body(Body::from(compressed))
+                    .unwrap(),
+                None => response.body(Body::from(content)).unwrap(),
+            }
+        }
+        // the frontend wasn't built, so explain that instead of 404ing
+        None if get("index.html").is_none() => Response::builder()
+            .status(if path == std::path::Path::new("index.html") {
+                StatusCode::OK
+            } else {
+                StatusCode::NOT_FOUND
+            })
+            .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+            .body(Body::from(PLACEHOLDER_PAGE))
+            .unwrap(),
+        None => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::from("Not Found"))
+            .unwrap(),
+    }
+}
+
+/// The asset to serve for a request `path`. With `spa_fallback`, paths that
+/// don't name an existing asset are served the app's `index.html`.
+fn asset_path(path: &str, spa_fallback: bool, exists: impl Fn(&str) -> bool) -> PathBuf {
+    let mut path = PathBuf::from(path.trim_start_matches("/"));
+    if path.file_name().is_none() {
+        path = path.join("index.html");
+    }
+    // client-side routes have no extension, unlike assets that are missing
+    if spa_fallback && path.extension().is_none() && !exists(path.to_str().unwrap()) {
+        path = PathBuf::from("index.html");
+    }
+    path
+}
+
+/// Content encodings of pre-compressed assets, by preference, with the file
+/// extension of each variant.
+const PRECOMPRESSED: &[(&str, &str)] = &[("br", "br"), ("gzip", "gz")];
+
+/// Whether the client's `Accept` header asks for HTML, as browsers loading
+/// a page do.
+fn accepts_html(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|item| {
+            let media_type = item.split(';').next().unwrap_or_default().trim();
+            media_type.eq_ignore_ascii_case("text/html")
+        })
+}
+
+/// Whether the client's `Accept-Encoding` allows `encoding`.
+fn accepts_encoding(headers: &HeaderMap, encoding: &str) -> bool {
+    headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|item| {
+            let mut params = item.split(';');
+            let name = params.next().unwrap_or_default().trim();
+            let quality = params
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .and_then(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            name.eq_ignore_ascii_case(encoding) && quality > 0.0
+        })
+}
+
+/// Served in place of the frontend when `web/dist/` was empty at build time.
+const PLACEHOLDER_PAGE: &str = include_str!("placeholder.html");
+
+#[derive(Embed)]
+#[folder = "web/dist/"]
+struct Asset;
+
+/// Writes every embedded asset below `dir`, keeping their paths, and returns
+/// how many there were.
+fn dump_assets(dir: &std::path::Path, overwrite: bool) -> std::io::Result<usize> {
+    let assets = Asset::iter().map(|path| {
+        let asset = Asset::get(&path).expect("iterated assets exist");
+        (path, asset.data)
+    });
+    write_assets(dir, overwrite, assets)
+}
+
+/// Writes `assets`, pairs of a path and its contents, below `dir`.
+fn write_assets(
+    dir: &std::path::Path,
+    overwrite: bool,
+    assets: impl IntoIterator<Item = (Cow<'static, str>, Cow<'static, [u8]>)>,
+) -> std::io::Result<usize> {
+    use std::io::Write;
+
+    let mut count = 0;
+    for (path, data) in assets {
+        let target = dir.join(path.as_ref());
+        let written = target
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|()| {
+                let mut options = std::fs::OpenOptions::new();
+                if overwrite {
+                    options.write(true).create(true).truncate(true);
+                } else {
+                    options.write(true).create_new(true);
+                }
+                options.open(&target)?.write_all(&data)
+            });
+        if let Err(err) = written {
+            let hint = if err.kind() == std::io::ErrorKind::AlreadyExists {
+                " (use --overwrite to replace it)"
+            } else {
+                ""
+            };
+            return Err(std::io::Error::new(
+                err.kind(),
+                format!("failed to write {}: {err}{hint}", target.display()),
+            ));
+        }
+        count += 1;
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpStream;
+    use tokio_tungstenite::WebSocketStream;
+    use tokio_tungstenite::tungstenite::{self, protocol::frame::coding::CloseCode};
+
+    use super::*;
+
+    fn command_spec(args: &[&str]) -> CommandSpec {
+        let args = RttydArgs::try_parse_from(std::iter::once("rttyd").chain(args.iter().copied()))
+            .unwrap();
+        args.command_spec().unwrap()
+    }
+
+    #[test]
+    fn single_argument_is_a_shell_script() {
+        match command_spec(&["top -d 1; echo done"]) {
+            CommandSpec::Shell { shell, script } => {
+                assert_eq!(shell, DEFAULT_SHELL);
+                assert_eq!(script, "top -d 1; echo done");
+            }
+            spec => panic!("expected a shell script, got {spec:?}"),
+        }
+    }
+
+    #[test]
+    fn several_arguments_are_passed_literally() {
+        match command_spec(&["--", "echo", "$(id)", ";", "rm -rf /"]) {
+            CommandSpec::Argv(argv) => assert_eq!(argv, ["echo", "$(id)", ";", "rm -rf /"]),
+            spec => panic!("expected argv, got {spec:?}"),
+        }
+    }
+
+    #[test]
+    fn no_shell_executes_single_argument() {
+        match command_spec(&["--no-shell", "top"]) {
+            CommandSpec::Argv(argv) => assert_eq!(argv, ["top"]),
+            spec => panic!("expected argv, got {spec:?}"),
+        }
+    }
+
+    #[test]
+    fn parses_resize_messages() {
+        assert_eq!(parse_size("24;80"), Some(rtty::Size::new(24, 80)));
+        assert_eq!(parse_size("0;0"), Some(rtty::Size::new(0, 0)));
+    }
+
+    #[test]
+    fn clamps_resizes() {
+        let clamped = |size| parse_size(size).unwrap().clamped();
+        assert_eq!(clamped("24;80"), rtty::Size::new(24, 80));
+        assert_eq!(clamped("0;0"), rtty::Size::new(1, 1));
+        assert_eq!(clamped("65535;65535"), rtty::Size::new(1000, 1000));
+        assert_eq!(clamped("1000;1001"), rtty::Size::new(1000, 1000));
+    }
+
+    #[test]
+    fn rejects_malformed_resize_messages() {
+        for size in [
+            "",
+            "24",
+            "24;",
+            ";80",
+            "24;80;1",
+            "-1;80",
+            "24;x",
+            "65536;80",
+            "２４;80",
+        ] {
+            assert_eq!(parse_size(size), None, "{size}");
+        }
+    }
+
+    fn accept_encoding(values: &[&'static str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(header::ACCEPT_ENCODING, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    /// The bytes an input message delivers to the command.
+    fn input_bytes(text: &str) -> Vec<u8> {
+        match parse_client_message(text).unwrap() {
+            ClientMessage::Input(data) => data,
+            ClientMessage::Text(data) => data.into_bytes(),
+            msg => panic!("not input: {msg:?}"),
+        }
+    }
+
+    #[test]
+    fn text_and_base64_input_deliver_the_same_bytes() {
+        for input in [
+            "ls -la\r",
+            "h\u{e9}llo w\u{f6}rld",
+            "\u{1f600}\u{4e2d}",
+            "\x1b[A\x03",
+        ] {
+            let encoded = base64::engine::general_purpose::STANDARD.encode(input);
+            let text = format!("1;{input}");
+            let base64 = format!("0;{encoded}");
+            assert_eq!(input_bytes(&text), input.as_bytes());
+            assert_eq!(input_bytes(&base64), input.as_bytes());
+            // typed text costs a third less sent as `1;`
+            assert!(text.len() < base64.len(), "{input:?}");
+        }
+    }
+
+    #[test]
+    fn text_input_keeps_multibyte_characters() {
+        for input in ["\u{e9}", "\u{1f600}", "\u{4e2d}\u{6587};\u{e9}"] {
+            assert_eq!(
+                parse_client_message(&format!("1;{input}")).unwrap(),
+                ClientMessage::Text(input.to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn parses_other_client_messages() {
+        assert_eq!(
+            parse_client_message("2;24;80").unwrap(),
+            ClientMessage::Resize(rtty::Size::new(24, 80))
+        );
+        assert_eq!(parse_client_message("4;").unwrap(), ClientMessage::Eof);
+        assert_eq!(
+            parse_client_message("a;").unwrap(),
+            ClientMessage::ScrollbackSize(None)
+        );
+        assert_eq!(
+            parse_client_message("a;100").unwrap(),
+            ClientMessage::ScrollbackSize(Some(100))
+        );
+        assert_eq!(
+            parse_client_message("b;").unwrap(),
+            ClientMessage::Replay(usize::MAX)
+        );
+        assert_eq!(
+            parse_client_message("b;10").unwrap(),
+            ClientMessage::Replay(10)
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_client_messages() {
+        for text in ["0;not base64!", "2;24", "a;x", "b;-1", "3;", "", "\u{e9};x"] {
+            assert!(parse_client_message(text).is_err(), "{text}");
+        }
+    }
+
+    #[test]
+    fn accepts_listed_encodings() {
+        let headers = accept_encoding(&["gzip, deflate, br"]);
+        assert!(accepts_encoding(&headers, "gzip"));
+        assert!(accepts_encoding(&headers, "br"));
+        assert!(!accepts_encoding(&headers, "zstd"));
+        assert!(accepts_encoding(&accept_encoding(&["GZIP"]), "gzip"));
+        assert!(accepts_encoding(
+            &accept_encoding(&["deflate", "gzip"]),
+            "gzip"
+        ));
+        assert!(!accepts_encoding(&HeaderMap::new(), "gzip"));
+    }
+
+    #[test]
+    fn honours_quality_values() {
+        assert!(accepts_encoding(&accept_encoding(&["gzip;q=0.5"]), "gzip"));
+        assert!(accepts_encoding(&accept_encoding(&["gzip; q=1"]), "gzip"));
+        assert!(!accepts_encoding(&accept_encoding(&["gzip;q=0"]), "gzip"));
+        assert!(!accepts_encoding(
+            &accept_encoding(&["br, gzip;q=0.0"]),
+            "gzip"
+        ));
+    }
+
+    #[test]
+    fn unescapes_init_input() {
+        assert_eq!(unescape("ls -l\\n").unwrap(), b"ls -l\n");
+        assert_eq!(unescape("a\\r\\tb\\\\c").unwrap(), b"a\r\tb\\c");
+        assert_eq!(unescape("\\e[A\\x03\\x7F").unwrap(), b"\x1b[A\x03\x7f");
+        assert_eq!(unescape("héllo").unwrap(), "héllo".as_bytes());
+        assert_eq!(unescape("").unwrap(), b"");
+    }
+
+    #[test]
+    fn rejects_bad_escapes() {
+        for text in ["\\", "a\\q", "\\x", "\\x4", "\\xg0", "\\xé"] {
+            assert!(unescape(text).is_err(), "{text}");
+        }
+    }
+
+    #[test]
+    fn once_session_is_claimed_until_released() {
+        let started = Arc::default();
+        let claim = OnceClaim::new(&started).unwrap();
+        assert!(OnceClaim::new(&started).is_none());
+        // a failed upgrade drops the claim
+        drop(claim);
+        let claim = OnceClaim::new(&started).unwrap();
+        claim.keep();
+        assert!(OnceClaim::new(&started).is_none());
+    }
+
+    /// One of each way a session can end.
+    fn session_ends() -> [SessionEnd; 8] {
+        [
+            SessionEnd::ClientClosed,
+            SessionEnd::ClientError("reset".into()),
+            SessionEnd::CommandExited(3),
+            SessionEnd::Aborted,
+            SessionEnd::DetachTimedOut,
+            SessionEnd::OutputIdleTimedOut,
+            SessionEnd::SpawnFailed("not found".into()),
+            SessionEnd::ServerStopped,
+        ]
+    }
+
+    #[test]
+    fn classifies_session_ends() {
+        let expected = [
+            // client_gone, must_abort, exit_code
+            (true, true, ABORTED_EXIT_CODE),
+            (true, true, ABORTED_EXIT_CODE),
+            (false, false, 3),
+            (false, false, ABORTED_EXIT_CODE),
+            (true, true, ABORTED_EXIT_CODE),
+            (false, true, ABORTED_EXIT_CODE),
+            (false, false, ABORTED_EXIT_CODE),
+            (false, true, ABORTED_EXIT_CODE),
+        ];
+        for (end, expected) in session_ends().iter().zip(expected) {
+            let actual = (end.client_gone(), end.must_abort(), end.exit_code());
+            assert_eq!(actual, expected, "{end:?}");
+        }
+        assert!(matches!(
+            SessionEnd::from(CommandExit::Exited(0)),
+            SessionEnd::CommandExited(0)
+        ));
+        assert!(matches!(
+            SessionEnd::from(CommandExit::Aborted),
+            SessionEnd::Aborted
+        ));
+    }
+
+    /// The code and reason of the close frame sent for `end`, if any.
+    fn close_reason(end: &SessionEnd, on_exit: OnExit) -> Option<(u16, String)> {
+        end.close_frame(on_exit)
+            .map(|frame| (frame.code, frame.reason.to_string()))
+    }
+
+    #[test]
+    fn close_frames_give_the_reason() {
+        let expected = [
+            None,
+            Some((close_code::ERROR, "Connection error")),
+            Some((close_code::NORMAL, "Command exited")),
+            Some((close_code::AWAY, "Command aborted")),
+            None,
+            Some((close_code::AWAY, "No output timeout")),
+            Some((close_code::ERROR, "Command failed to start")),
+            Some((close_code::AWAY, "Server shutting down")),
+        ];
+        for (end, expected) in session_ends().iter().zip(expected) {
+            let expected = expected.map(|(code, reason)| (code, reason.to_string()));
+            assert_eq!(close_reason(end, OnExit::Close), expected, "{end:?}");
+        }
+    }
+
+    #[test]
+    fn on_exit_decides_how_exits_end_the_socket() {
+        let exited = SessionEnd::CommandExited(0);
+        let expected = [
+            // close frame, socket held open
+            (OnExit::Close, Some(close_code::NORMAL), false),
+            (OnExit::Hold, None, true),
+            (OnExit::Reconnect, Some(close_code::RESTART), false),
+        ];
+        for (on_exit, code, holds) in expected {
+            let frame = exited.close_frame(on_exit);
+            assert_eq!(frame.map(|frame| frame.code), code, "{on_exit:?}");
+            assert_eq!(exited.holds_socket(on_exit), holds, "{on_exit:?}");
+        }
+        // other ends don't depend on it
+        for end in session_ends()
+            .iter()
+            .filter(|end| !matches!(end, SessionEnd::CommandExited(_)))
+        {
+            let close = close_reason(end, OnExit::Close);
+            for on_exit in [OnExit::Hold, OnExit::Reconnect] {
+                assert_eq!(close_reason(end, on_exit), close, "{end:?} {on_exit:?}");
+                assert!(!end.holds_socket(on_exit), "{end:?} {on_exit:?}");
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn env_clear_keeps_secrets_from_commands() {
+        // SAFETY: no other test reads or writes these variables
+        unsafe {
+            std::env::set_var("RTTYD_TEST_SECRET", "hunter2");
+            std::env::set_var("RTTYD_TEST_PASSED", "passed");
+        }
+        let args = RttydArgs::try_parse_from([
+            "rttyd",
+            "--env-clear",
+            "--env-passthrough",
+            "RTTYD_TEST_PASSED",
+            "--env",
+            "RTTYD_TEST_SET=set",
+            "env",
+        ])
+        .unwrap();
+        let env = args.command_env();
+        let keys = env
+            .vars
+            .iter()
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        assert!(!keys.contains(&"RTTYD_TEST_SECRET".into()), "{keys:?}");
+
+        let command = args.command_spec().unwrap().to_command(&env);
+        let (mut output, _input) =
+            start_command(command, Arc::default(), CommandOptions::default()).unwrap();
+        let mut read = Vec::new();
+        while let Some(item) = output.next().await {
+            if let CommandOutputItem::Output(data) = item {
+                read.extend_from_slice(&data);
+            }
+        }
+        let read = String::from_utf8_lossy(&read);
+        assert!(!read.contains("hunter2"), "{read}");
+        assert!(read.contains("RTTYD_TEST_PASSED=passed"), "{read}");
+        assert!(read.contains("RTTYD_TEST_SET=set"), "{read}");
+        assert!(read.contains("PATH="), "{read}");
+    }
+
+    #[test]
+    fn spa_fallback_serves_index_for_client_routes() {
+        let exists = |name: &str| matches!(name, "index.html" | "app.js" | "docs");
+        let resolve = |path, spa_fallback| asset_path(path, spa_fallback, exists);
+        assert_eq!(
+            resolve("/some/route", true),
+            std::path::Path::new("index.html")
+        );
+        assert_eq!(resolve("/", true), std::path::Path::new("index.html"));
+        assert_eq!(resolve("/app.js", true), std::path::Path::new("app.js"));
+        assert_eq!(resolve("/docs", true), std::path::Path::new("docs"));
+        // missing assets still 404
+        assert_eq!(resolve("/x.js", true), std::path::Path::new("x.js"));
+        // as do routes without --spa or requests not asking for HTML
+        assert_eq!(
+            resolve("/some/route", false),
+            std::path::Path::new("some/route")
+        );
+    }
+
+    #[test]
+    fn restart_backoff_doubles_up_to_a_cap() {
+        let delays = (0..9).map(restart_backoff).collect::<Vec<_>>();
+        let millis = [250, 500, 1000, 2000, 4000, 8000, 10_000, 10_000, 10_000];
+        assert_eq!(delays, millis.map(Duration::from_millis));
+        assert_eq!(restart_backoff(u32::MAX), Duration::from_secs(10));
+    }
+
+    /// Log output shared with a subscriber writing into it.
+    #[derive(Clone, Default)]
+    struct LogBuffer(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for LogBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn session_logs_carry_the_session_id() {
+        let logs = LogBuffer::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        let peer = PeerAddr::Tcp(([127, 0, 0, 1], 40000).into());
+        let ids = [Uuid::new_v4(), Uuid::new_v4()];
+        tracing::subscriber::with_default(subscriber, || {
+            for id in ids {
+                let span = session_span(id, peer.ip(), Some("logs"));
+                span.in_scope(|| log_connected(peer, &HeaderMap::new()));
+                span.in_scope(|| log_session_end(&SessionEnd::ClientClosed));
+            }
+        });
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        let lines = logs.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 4, "{logs}");
+        for (id, lines) in ids.iter().zip(lines.chunks(2)) {
+            let id = format!("id={id}");
+            assert!(lines[0].contains(&id), "{}", lines[0]);
+            assert!(lines[0].contains("Client connected"), "{}", lines[0]);
+            assert!(lines[1].contains(&id), "{}", lines[1]);
+            assert!(lines[1].contains("Client closed"), "{}", lines[1]);
+        }
+    }
+
+    /// Serves rttyd with the command line `args` on a free loopback port.
+    async fn serve(args: &[&str]) -> (std::net::SocketAddr, Arc<AppState>) {
+        let args = RttydArgs::try_parse_from(std::iter::once("rttyd").chain(args.iter().copied()))
+            .unwrap();
+        serve_args(args).await
+    }
+
+    async fn serve_args(args: RttydArgs) -> (std::net::SocketAddr, Arc<AppState>) {
+        let state = Arc::new(args.app_state(None));
+        let app = args.router().with_state(state.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<PeerAddr>(),
+        );
+        tokio::spawn(server.into_future());
+        (addr, state)
+    }
+
+    type Client = tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<TcpStream>>;
+
+    async fn connect(url: &str) -> Client {
+        tokio_tungstenite::connect_async(url).await.unwrap().0
+    }
+
+    /// The next message from the server, failing the test if none arrives
+    /// within a few seconds.
+    async fn receive<S>(client: &mut WebSocketStream<S>) -> tungstenite::Message
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
+        tokio::time::timeout(Duration::from_secs(5), client.next())
+            .await
+            .expect("timed out waiting for a message")
+            .expect("connection closed")
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn oversized_messages_end_the_session_with_an_error() {
+        let (addr, _) = serve(&["--max-message-bytes", "1024", "sleep", "10"]).await;
+        let url = format!("ws://{addr}/ws");
+        for message in [
+            format!("1;{}", "x".repeat(2000)),
+            format!("0;{}", "eHh4".repeat(500)),
+        ] {
+            let mut client = connect(&url).await;
+            client.send(message.into()).await.unwrap();
+            let close = loop {
+                if let tungstenite::Message::Close(frame) = receive(&mut client).await {
+                    break frame;
+                }
+            };
+            // sent from the end of the session, so handling it didn't panic
+            assert_eq!(close.unwrap().code, CloseCode::Error);
+        }
+    }
+
+    /// Terminal output received until it contains `needle`.
+    #[cfg(unix)]
+    async fn output_until<S>(client: &mut WebSocketStream<S>, needle: &str) -> String
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
+        let mut output = String::new();
+        while !output.contains(needle) {
+            if let tungstenite::Message::Binary(data) = receive(client).await {
+                output.push_str(&String::from_utf8_lossy(&data));
+            }
+        }
+        output
+    }
+
+    /// A fresh directory for a test to write into.
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("rttyd-{name}-{}", Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn parses_octal_modes() {
+        assert_eq!(parse_mode("660"), Ok(0o660));
+        assert_eq!(parse_mode("0755"), Ok(0o755));
+        for mode in ["", "rw", "999", "1777", "-1"] {
+            assert!(parse_mode(mode).is_err(), "{mode}");
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn serves_websockets_on_a_unix_socket() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = temp_dir("unix-socket");
+        let path = dir.join("rttyd.sock");
+        std::fs::write(&path, "").unwrap();
+        let err = bind_unix_socket(&path, 0o600).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+        std::fs::remove_file(&path).unwrap();
+        // a stale socket from an earlier run is replaced
+        drop(bind_unix_socket(&path, 0o600).unwrap());
+        let listener = bind_unix_socket(&path, 0o600).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let args = RttydArgs::try_parse_from(["rttyd", "echo", "unix hello"]).unwrap();
+        let app = args.router().with_state(Arc::new(args.app_state(None)));
+        let server = axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<PeerAddr>(),
+        );
+        tokio::spawn(server.into_future());
+        let stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        let (mut client, _) = tokio_tungstenite::client_async("ws://localhost/ws", stream)
+            .await
+            .unwrap();
+        output_until(&mut client, "unix hello").await;
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn status_messages_are_json() {
+        let status = SessionStatus {
+            bytes_in: 7,
+            bytes_out: 1024,
+            uptime_secs: 3,
+        };
+        assert_eq!(
+            serde_json::to_string(&status).unwrap(),
+            r#"{"bytes_in":7,"bytes_out":1024,"uptime_secs":3}"#
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn sends_status_messages_with_byte_counts() {
+        let (addr, _) = serve(&["--status-interval", "1", "cat"]).await;
+        let mut client = connect(&format!("ws://{addr}/ws")).await;
+        client.send("1;ping\n".into()).await.unwrap();
+        output_until(&mut client, "ping").await;
+        let status = loop {
+            if let tungstenite::Message::Text(text) = receive(&mut client).await
+                && let Some(status) = text.strip_prefix("6;")
+            {
+                let status: serde_json::Value = serde_json::from_str(status).unwrap();
+                if status["bytes_out"].as_u64() > Some(0) {
+                    break status;
+                }
+            }
+        };
+        // the whole message, prefix included
+        assert_eq!(status["bytes_in"], 7);
+        // echoed by the terminal and written back by cat
+        assert!(status["bytes_out"].as_u64() >= Some(12), "{status}");
+        assert!(status["uptime_secs"].as_u64() >= Some(1), "{status}");
+    }
+
+    /// Sends a raw HTTP/1.1 request and returns the whole response.
+    async fn http(addr: std::net::SocketAddr, request: &str) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    fn post_input(id: &str, token: Option<&str>, body: &str) -> String {
+        let auth = token
+            .map(|token| format!("Authorization: Bearer {token}\r\n"))
+            .unwrap_or_default();
+        format!(
+            "POST /sessions/{id}/input HTTP/1.1\r\nHost: localhost\r\n{auth}\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn control_api_writes_to_sessions() {
+        let (addr, _) = serve(&["--enable-control-api", "--auth-token", "secret", "cat"]).await;
+        let (mut client, response) =
+            tokio_tungstenite::connect_async(format!("ws://{addr}/ws?token=secret"))
+                .await
+                .unwrap();
+        let id = response.headers()[SESSION_ID_HEADER].to_str().unwrap();
+
+        let response = http(addr, &post_input(id, Some("secret"), "from api\n")).await;
+        assert!(response.starts_with("HTTP/1.1 200 "), "{response}");
+        output_until(&mut client, "from api").await;
+
+        for token in [None, Some("wrong")] {
+            let response = http(addr, &post_input(id, token, "denied\n")).await;
+            assert!(response.starts_with("HTTP/1.1 401 "), "{response}");
+        }
+        let unknown = Uuid::new_v4().to_string();
+        for id in [unknown.as_str(), "not-a-uuid"] {
+            let response = http(addr, &post_input(id, Some("secret"), "lost\n")).await;
+            assert!(response.starts_with("HTTP/1.1 404 "), "{response}");
+        }
+    }
+
+    #[tokio::test]
+    async fn control_api_is_off_by_default() {
+        let (addr, _) = serve(&["--auth-token", "secret", "cat"]).await;
+        let id = Uuid::new_v4().to_string();
+        let response = http(addr, &post_input(&id, Some("secret"), "x")).await;
+        // falls through to the static assets, which only answer GET
+        assert!(response.starts_with("HTTP/1.1 405 "), "{response}");
+    }
+
+    #[tokio::test]
+    async fn plain_requests_to_websocket_routes_need_an_upgrade() {
+        let (addr, _) = serve(&["--route", "logs=cat", "cat"]).await;
+        for path in ["/ws", "/ws/logs"] {
+            let request =
+                format!("GET {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+            let response = http(addr, &request).await;
+            assert!(
+                response.starts_with("HTTP/1.1 426 Upgrade Required\r\n"),
+                "{response}"
+            );
+            assert!(
+                response.contains("\r\nupgrade: websocket\r\n"),
+                "{response}"
+            );
+            assert!(response.ends_with(NOT_A_WEBSOCKET), "{response}");
+        }
+        let request = "GET /ws/nope HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+        let response = http(addr, request).await;
+        assert!(response.starts_with("HTTP/1.1 404 "), "{response}");
+    }
+
+    async fn get_config(args: &[&str]) -> serde_json::Value {
+        let (addr, _) = serve(args).await;
+        let request = "GET /config HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+        let response = http(addr, request).await;
+        assert!(response.starts_with("HTTP/1.1 200 "), "{response}");
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        serde_json::from_str(body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn config_reports_title_and_version() {
+        let config = get_config(&["--title", "build box", "--on-exit", "hold", "cat"]).await;
+        assert_eq!(
+            config,
+            serde_json::json!({
+                "title": "build box",
+                "version": env!("PKG_LONG_VERSION"),
+                "on_exit": "hold",
+            })
+        );
+        let config = get_config(&["cat"]).await;
+        assert_eq!(config["title"], serde_json::Value::Null);
+        assert_eq!(config["on_exit"], "close");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn runs_scripts_from_command_files() {
+        let dir = temp_dir("command-file");
+        let path = dir.join("script.sh");
+        std::fs::write(&path, "marker=script\necho \"$marker ran\"\n").unwrap();
+        let path = path.to_str().unwrap();
+        let mut args = RttydArgs::try_parse_from(["rttyd", "--command-file", path]).unwrap();
+        args.read_files().unwrap();
+        let (addr, _) = serve_args(args).await;
+        let mut client = connect(&format!("ws://{addr}/ws")).await;
+        output_until(&mut client, "script ran").await;
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn command_files_must_be_readable() {
+        let mut args =
+            RttydArgs::try_parse_from(["rttyd", "--command-file", "/no/such/script"]).unwrap();
+        let err = args.read_files().unwrap_err();
+        assert!(err.starts_with("failed to read /no/such/script: "), "{err}");
+        let err =
+            RttydArgs::try_parse_from(["rttyd", "--command-file", "x.sh", "top"]).unwrap_err();
+        assert_eq!(err.kind(), clap::error::ErrorKind::ArgumentConflict);
+    }
+
+    /// Status and body of the response to a GET of `uri` from the assets
+    /// `files`.
+    async fn get_asset(files: &[(&str, &'static str)], uri: &str) -> (StatusCode, String) {
+        let get = |name: &str| {
+            files
+                .iter()
+                .find(|(file, _)| *file == name)
+                .map(|(_, content)| Cow::Borrowed(content.as_bytes()))
+        };
+        let path = asset_path(uri, false, |name| get(name).is_some());
+        let response = asset_response(&path, &HeaderMap::new(), get);
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn serves_a_placeholder_without_assets() {
+        let (status, body) = get_asset(&[], "/").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, PLACEHOLDER_PAGE);
+        let (status, body) = get_asset(&[], "/app.js").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body, PLACEHOLDER_PAGE);
+        // unknown paths still 404 plainly once the frontend is built
+        let files = [("index.html", "<html>"), ("app.js", "app")];
+        assert_eq!(
+            get_asset(&files, "/").await,
+            (StatusCode::OK, "<html>".into())
+        );
+        assert_eq!(
+            get_asset(&files, "/nope.js").await,
+            (StatusCode::NOT_FOUND, "Not Found".into())
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn once_exits_with_the_command_code() {
+        for (command, client_leaves, code) in [("exit 3", false, 3), ("sleep 10", true, 125)] {
+            let (addr, state) = serve(&["--once", command]).await;
+            let stopped = state.shutdown.notified();
+            let url = format!("ws://{addr}/ws");
+            let mut client = connect(&url).await;
+            if client_leaves {
+                client.close(None).await.unwrap();
+            }
+            tokio::time::timeout(Duration::from_secs(5), stopped)
+                .await
+                .expect("server wasn't told to stop");
+            assert_eq!(state.exit_code.get(), Some(&code), "{command}");
+            // the one session is used up
+            let err = tokio_tungstenite::connect_async(&url).await.unwrap_err();
+            let tungstenite::Error::Http(response) = err else {
+                panic!("{err}");
+            };
+            assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        }
+    }
+
+    #[test]
+    fn terminal_text_ends_lines_with_crlf() {
+        assert_eq!(terminal_text("a\nb\r\nc\n"), "a\r\nb\r\nc\r\n");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn banner_arrives_before_command_output() {
+        let dir = temp_dir("banner");
+        let path = dir.join("banner.txt");
+        std::fs::write(&path, "Authorized use only\nsecond line\n").unwrap();
+        let banner = format!("@{}", path.display());
+        let mut args =
+            RttydArgs::try_parse_from(["rttyd", "--banner", &banner, "echo command output"])
+                .unwrap();
+        args.read_files().unwrap();
+        let (addr, _) = serve_args(args).await;
+        let mut client = connect(&format!("ws://{addr}/ws")).await;
+        let tungstenite::Message::Binary(first) = receive(&mut client).await else {
+            panic!("expected the banner first");
+        };
+        assert_eq!(first, "Authorized use only\r\nsecond line\r\n");
+        output_until(&mut client, "command output").await;
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn command_errors_reach_the_client() {
+        let (addr, _) = serve(&["/no/such/program", "arg"]).await;
+        let mut client = connect(&format!("ws://{addr}/ws")).await;
+        let tungstenite::Message::Text(text) = receive(&mut client).await else {
+            panic!("expected an error message");
+        };
+        assert!(text.starts_with("9;No such file"), "{text}");
+        let tungstenite::Message::Close(Some(frame)) = receive(&mut client).await else {
+            panic!("expected the socket to close");
+        };
+        assert_eq!(frame.code, CloseCode::Error);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn sends_keepalive_pings_while_idle() {
+        let (addr, _) = serve(&["--keepalive", "1", "sleep 10"]).await;
+        let mut client = connect(&format!("ws://{addr}/ws")).await;
+        let connected = Instant::now();
+        let mut pings = Vec::new();
+        while pings.len() < 2 {
+            if let tungstenite::Message::Ping(data) = receive(&mut client).await {
+                assert!(data.is_empty());
+                pings.push(connected.elapsed());
+            }
+        }
+        // one a second, the first a second in
+        for (ping, expected) in pings.iter().zip([1, 2]) {
+            let expected = Duration::from_secs(expected);
+            assert!(
+                ping.abs_diff(expected) < Duration::from_millis(300),
+                "{pings:?}"
+            );
+        }
+    }
+
+    /// Whether `pid` is a live process, rather than gone or a zombie.
+    #[cfg(target_os = "linux")]
+    fn process_running(pid: u32) -> bool {
+        let Ok(stat) = std::fs::read_to_string(format!("/proc/{pid}/stat")) else {
+            return false;
+        };
+        let state = stat.rsplit_once(") ").map(|(_, rest)| &rest[..1]);
+        !matches!(state, Some("Z" | "X"))
+    }
+
+    /// Resets the connection under `client` instead of closing it.
+    #[cfg(target_os = "linux")]
+    fn reset(client: Client) {
+        if let tokio_tungstenite::MaybeTlsStream::Plain(stream) = client.get_ref() {
+            stream.set_linger(Some(Duration::ZERO)).unwrap();
+        }
+    }
+
+    /// Waits until no session is left running on the server.
+    #[cfg(target_os = "linux")]
+    async fn sessions_ended(state: &AppState) {
+        let mut live = state.live_sessions.subscribe();
+        tokio::time::timeout(Duration::from_secs(5), live.wait_for(|live| *live == 0))
+            .await
+            .expect("session didn't end")
+            .unwrap();
+    }
+
+    /// The pid a command prints as `pid <pid>` on its first line.
+    #[cfg(target_os = "linux")]
+    async fn printed_pid(client: &mut Client) -> u32 {
+        let output = output_until(client, "\n").await;
+        let line = output.lines().next().unwrap();
+        line.strip_prefix("pid ")
+            .unwrap()
+            .trim_end()
+            .parse()
+            .unwrap()
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn vanished_clients_abort_their_command() {
+        let (addr, state) = serve(&["echo \"pid $$\"; exec yes"]).await;
+        let mut client = connect(&format!("ws://{addr}/ws")).await;
+        let pid = printed_pid(&mut client).await;
+        reset(client);
+        sessions_ended(&state).await;
+        assert!(!process_running(pid));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn vanished_clients_detach_with_detach_on_disconnect() {
+        let (addr, state) = serve(&[
+            "--detach-on-disconnect",
+            "--detach-timeout",
+            "1",
+            "echo \"pid $$\"; exec yes",
+        ])
+        .await;
+        let url = format!("ws://{addr}/ws");
+        let (mut client, response) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        let id = response.headers()[SESSION_ID_HEADER].to_str().unwrap();
+        let pid = printed_pid(&mut client).await;
+        reset(client);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(process_running(pid));
+        // the session still runs for a client to pick up
+        let mut client = connect(&format!("{url}?session={id}")).await;
+        output_until(&mut client, "y\r\ny").await;
+        reset(client);
+        sessions_ended(&state).await;
+        assert!(!process_running(pid));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn silent_commands_are_killed_after_the_output_idle_timeout() {
+        let (addr, state) = serve(&[
+            "--output-idle-timeout",
+            "1",
+            "echo \"pid $$\"; exec sleep 100",
+        ])
+        .await;
+        let mut client = connect(&format!("ws://{addr}/ws")).await;
+        let pid = printed_pid(&mut client).await;
+        let started = Instant::now();
+        let close = loop {
+            if let tungstenite::Message::Close(frame) = receive(&mut client).await {
+                break frame.unwrap();
+            }
+        };
+        assert_eq!(close.code, CloseCode::Away);
+        assert_eq!(close.reason, "No output timeout");
+        assert!(started.elapsed() < Duration::from_secs(2));
+        sessions_ended(&state).await;
+        assert!(!process_running(pid));
+    }
+
+    /// Status of a GET of `path` over cleartext HTTP/2 (prior knowledge).
+    async fn get_http2(addr: std::net::SocketAddr, path: &str) -> StatusCode {
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (client, connection) = h2::client::handshake(stream).await.unwrap();
+        tokio::spawn(connection);
+        let mut client = client.ready().await.unwrap();
+        let request = axum::http::Request::get(format!("http://{addr}{path}"))
+            .body(())
+            .unwrap();
+        let (response, _) = client.send_request(request, true).unwrap();
+        let response = response.await.unwrap();
+        assert_eq!(response.version(), axum::http::Version::HTTP_2);
+        response.status()
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn http2_cleartext_serves_assets_beside_websockets() {
+        let (addr, _) = serve(&["--http2-cleartext", "echo over http1"]).await;
+        assert_eq!(get_http2(addr, "/config").await, StatusCode::OK);
+        assert_eq!(get_http2(addr, "/").await, StatusCode::OK);
+        let mut client = connect(&format!("ws://{addr}/ws")).await;
+        output_until(&mut client, "over http1").await;
+    }
+
+    #[tokio::test]
+    async fn http2_is_refused_by_default() {
+        let (addr, _) = serve(&["cat"]).await;
+        assert_eq!(
+            get_http2(addr, "/config").await,
+            StatusCode::HTTP_VERSION_NOT_SUPPORTED
+        );
+    }
+
+    #[test]
+    fn announces_listening_as_json_on_request() {
+        let url = "http://127.0.0.1:7681";
+        assert_eq!(
+            listening_message(url, false),
+            "Listening on http://127.0.0.1:7681"
+        );
+        let message = listening_message(url, true);
+        assert!(!message.contains('\n'), "{message}");
+        let event: serde_json::Value = serde_json::from_str(&message).unwrap();
+        assert_eq!(
+            event,
+            serde_json::json!({
+                "event": "listening",
+                "url": url,
+                "version": env!("PKG_LONG_VERSION"),
+                "pid": std::process::id(),
+            })
+        );
+    }
+
+    #[test]
+    fn dumps_assets_keeping_their_paths() {
+        let dir = temp_dir("dump-assets");
+        let assets = |index: &'static str| {
+            [("index.html", index), ("assets/app.js", "app")]
+                .map(|(path, data)| (Cow::Borrowed(path), Cow::Borrowed(data.as_bytes())))
+        };
+        assert_eq!(write_assets(&dir, false, assets("<html>")).unwrap(), 2);
+        let read = |path| std::fs::read_to_string(dir.join(path)).unwrap();
+        assert_eq!(read("index.html"), "<html>");
+        assert_eq!(read("assets/app.js"), "app");
+
+        let err = write_assets(&dir, false, assets("<new>")).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+        assert!(
+            err.to_string().ends_with("(use --overwrite to replace it)"),
+            "{err}"
+        );
+        assert_eq!(read("index.html"), "<html>");
+        assert_eq!(write_assets(&dir, true, assets("<new>")).unwrap(), 2);
+        assert_eq!(read("index.html"), "<new>");
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // whatever this build embeds comes out unchanged
+        let dir = temp_dir("dump-assets");
+        assert_eq!(dump_assets(&dir, false).unwrap(), Asset::iter().count());
+        for path in Asset::iter() {
+            let written = std::fs::read(dir.join(path.as_ref())).unwrap();
+            assert_eq!(written, Asset::get(&path).unwrap().data.as_ref());
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn parses_dump_assets() {
+        let args =
+            RttydArgs::try_parse_from(["rttyd", "dump-assets", "out", "--overwrite"]).unwrap();
+        let Some(RttydCommand::DumpAssets { dir, overwrite }) = args.subcommand else {
+            panic!("expected dump-assets");
+        };
+        assert_eq!(dir, std::path::Path::new("out"));
+        assert!(overwrite);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn shared_sessions_broadcast_to_read_only_viewers() {
+        let (addr, _) = serve(&["--shared", "cat"]).await;
+        let url = format!("ws://{addr}/ws");
+        let (mut writer, response) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        let id = response.headers()[SESSION_ID_HEADER].to_str().unwrap();
+        writer.send("1;before\n".into()).await.unwrap();
+        output_until(&mut writer, "before\r\nbefore").await;
+
+        let mut viewers = Vec::new();
+        for _ in 0..2 {
+            let mut viewer = connect(&format!("{url}?session={id}")).await;
+            // joining viewers catch up on the scrollback first
+            let tungstenite::Message::Binary(replay) = receive(&mut viewer).await else {
+                panic!("expected the scrollback");
+            };
+            assert!(replay.starts_with(b"before\r\nbefore"), "{replay:?}");
+            viewers.push(viewer);
+        }
+        writer.send("1;live\n".into()).await.unwrap();
+        output_until(&mut writer, "live\r\nlive").await;
+        for viewer in &mut viewers {
+            output_until(viewer, "live\r\nlive").await;
+        }
+
+        viewers[0].send("1;from viewer\n".into()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        writer.send("1;done\n".into()).await.unwrap();
+        let output = output_until(&mut writer, "done\r\ndone").await;
+        assert!(!output.contains("from viewer"), "{output}");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn serves_websockets_on_every_host() {
+        let hosts = ["127.0.0.1".to_string(), "::1".to_string()];
+        let listeners = bind_hosts(&hosts, 0).await.unwrap();
+        let args = RttydArgs::try_parse_from(["rttyd", "echo hello"]).unwrap();
+        let app = args.router().with_state(Arc::new(args.app_state(None)));
+        let mut urls = Vec::new();
+        for listener in listeners {
+            urls.push(format!("ws://{}/ws", listener.local_addr().unwrap()));
+            let app = app
+                .clone()
+                .into_make_service_with_connect_info::<PeerAddr>();
+            tokio::spawn(axum::serve(listener, app).into_future());
+        }
+        assert!(urls[1].starts_with("ws://[::1]:"), "{urls:?}");
+        for url in urls {
+            let mut client = connect(&url).await;
+            output_until(&mut client, "hello").await;
+        }
+    }
+
+    #[tokio::test]
+    async fn binds_all_hosts_or_none() {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        // the second listener can't have the port the first one holds
+        let hosts = ["127.0.0.1".to_string(), "127.0.0.1".to_string()];
+        let err = bind_hosts(&hosts, port).await.unwrap_err();
+        assert!(
+            err.starts_with(&format!("failed to bind 127.0.0.1 port {port}: ")),
+            "{err}"
+        );
+        // and the first let go of it
+        TcpListener::bind(("127.0.0.1", port)).await.unwrap();
+    }
+}
@@ -0,0 +1,128 @@
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+
+use axum::extract::connect_info::Connected;
+use axum::http::HeaderMap;
+use axum::serve::IncomingStream;
+use tokio::net::TcpListener;
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+/// The remote end of a connection, for whichever listener accepted it.
+#[derive(Clone, Copy, Debug)]
+pub enum PeerAddr {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix,
+}
+
+impl PeerAddr {
+    /// Unix socket peers are always local, so they count as loopback.
+    pub fn ip(&self) -> IpAddr {
+        match self {
+            Self::Tcp(addr) => addr.ip(),
+            #[cfg(unix)]
+            Self::Unix => IpAddr::V4(std::net::Ipv4Addr::LOCALHOST),
+        }
+    }
+}
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => addr.fmt(f),
+            #[cfg(unix)]
+            Self::Unix => f.write_str("unix"),
+        }
+    }
+}
+
+impl Connected<IncomingStream<'_, TcpListener>> for PeerAddr {
+    fn connect_info(stream: IncomingStream<'_, TcpListener>) -> Self {
+        Self::Tcp(*stream.remote_addr())
+    }
+}
+
+#[cfg(unix)]
+impl Connected<IncomingStream<'_, tokio::net::UnixListener>> for PeerAddr {
+    fn connect_info(_stream: IncomingStream<'_, tokio::net::UnixListener>) -> Self {
+        Self::Unix
+    }
+}
+
+/// Returns the address of the client behind a connection.
+///
+/// The peer is used unless `trust_proxy` is set, in which case the
+/// right-most `X-Forwarded-For` address wins when present: the one the proxy
+/// in front of rttyd appended. Entries further left were sent by the client
+/// and can't be trusted.
+pub fn client_ip(peer: PeerAddr, headers: &HeaderMap, trust_proxy: bool) -> IpAddr {
+    if trust_proxy
+        && let Some(ip) = headers
+            .get_all(X_FORWARDED_FOR)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .next_back()
+            .and_then(|ip| ip.trim().parse().ok())
+    {
+        return ip;
+    }
+    peer.ip()
+}
+
+/// Returns the raw `X-Forwarded-For` header, if any.
+pub fn forwarded_for(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(X_FORWARDED_FOR)
+        .and_then(|value| value.to_str().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer() -> PeerAddr {
+        PeerAddr::Tcp("10.0.0.1:5000".parse().unwrap())
+    }
+
+    fn headers(values: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(X_FORWARDED_FOR, value.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn ignores_header_unless_trusted() {
+        let headers = headers(&["203.0.113.7"]);
+        assert_eq!(client_ip(peer(), &headers, false), peer().ip());
+    }
+
+    #[test]
+    fn takes_address_appended_by_proxy() {
+        // the client sent the first entry itself
+        let headers = headers(&["1.2.3.4, 203.0.113.7"]);
+        assert_eq!(
+            client_ip(peer(), &headers, true),
+            "203.0.113.7".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn takes_last_of_repeated_headers() {
+        let headers = headers(&["1.2.3.4", "5.6.7.8, 2001:db8::1"]);
+        assert_eq!(
+            client_ip(peer(), &headers, true),
+            "2001:db8::1".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn falls_back_to_peer() {
+        assert_eq!(client_ip(peer(), &HeaderMap::new(), true), peer().ip());
+        let headers = headers(&["1.2.3.4, garbage"]);
+        assert_eq!(client_ip(peer(), &headers, true), peer().ip());
+    }
+}
@@ -0,0 +1,313 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    net::IpAddr,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+/// A rate of `count` events per `period`, written as `<N>/<SECONDS>`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rate {
+    pub count: u32,
+    pub period: Duration,
+}
+
+impl FromStr for Rate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (count, secs) = s
+            .split_once('/')
+            .ok_or_else(|| format!("expected <N>/<SECONDS>, got `{s}`"))?;
+        let count = count
+            .parse()
+            .ok()
+            .filter(|count| *count > 0)
+            .ok_or_else(|| format!("invalid count `{count}`"))?;
+        let secs = secs
+            .parse()
+            .ok()
+            .filter(|secs| *secs > 0)
+            .ok_or_else(|| format!("invalid number of seconds `{secs}`"))?;
+        Ok(Self {
+            count,
+            period: Duration::from_secs(secs),
+        })
+    }
+}
+
+impl fmt::Display for Rate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.count, self.period.as_secs())
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token-bucket limiter keyed by client address. Each address may burst up
+/// to `rate.count` connections and regains one every
+/// `rate.period / rate.count`.
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: Rate,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: Rate) -> Arc<Self> {
+        let limiter = Arc::new(Self {
+            rate,
+            buckets: Mutex::default(),
+        });
+        let weak = Arc::downgrade(&limiter);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(rate.period);
+            loop {
+                interval.tick().await;
+                let Some(limiter) = weak.upgrade() else {
+                    break;
+                };
+                limiter.cleanup();
+            }
+        });
+        limiter
+    }
+
+    /// Takes a token for `ip`, returning false if it has none left.
+    pub fn check(&self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        let capacity = f64::from(self.rate.count);
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        bucket.tokens = self.refill(bucket, now);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn refill(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        let per_sec = f64::from(self.rate.count) / self.rate.period.as_secs_f64();
+        (bucket.tokens + elapsed * per_sec).min(f64::from(self.rate.count))
+    }
+
+    /// Forgets addresses whose bucket has refilled completely, as they are
+    /// indistinguishable from ones never seen.
+    fn cleanup(&self) {
+        let now = Instant::now();
+        let capacity = f64::from(self.rate.count);
+        self.buckets
+            .lock()
+            .unwrap()
+            .retain(|_, bucket| self.refill(bucket, now) < capacity);
+    }
+}
+
+/// Token bucket limiting a session's output to `rate` bytes per second,
+/// allowing bursts of up to one second's worth.
+#[derive(Debug)]
+pub struct OutputThrottle {
+    rate: f64,
+    bucket: Bucket,
+}
+
+impl OutputThrottle {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate: rate as f64,
+            bucket: Bucket {
+                tokens: rate as f64,
+                updated: Instant::now(),
+            },
+        }
+    }
+
+    /// Accounts for `len` bytes sent. A chunk larger than the remaining
+    /// budget is let through and paid off before the next one, so the
+    /// average rate holds however the output is split.
+    pub fn consume(&mut self, len: usize) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.bucket.updated).as_secs_f64();
+        self.bucket.tokens = (self.bucket.tokens + elapsed * self.rate).min(self.rate);
+        self.bucket.updated = now;
+        self.bucket.tokens -= len as f64;
+    }
+
+    /// When more output may be sent, if not yet.
+    pub fn ready_at(&self) -> Option<Instant> {
+        if self.bucket.tokens >= 0.0 {
+            return None;
+        }
+        let debt = Duration::from_secs_f64(-self.bucket.tokens / self.rate);
+        Some(self.bucket.updated + debt).filter(|ready| *ready > Instant::now())
+    }
+}
+
+/// Caps the number of sessions each client address may have open at once.
+#[derive(Debug)]
+pub struct ClientLimiter {
+    max: usize,
+    sessions: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl ClientLimiter {
+    pub fn new(max: usize) -> Self {
+        Self {
+            max,
+            sessions: Arc::default(),
+        }
+    }
+
+    /// Counts a new session for `ip` until the returned slot is dropped, or
+    /// returns `None` if `ip` already has the maximum number of sessions.
+    pub fn acquire(&self, ip: IpAddr) -> Option<ClientSlot> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let count = sessions.entry(ip).or_default();
+        if *count >= self.max {
+            return None;
+        }
+        *count += 1;
+        Some(ClientSlot {
+            ip,
+            sessions: self.sessions.clone(),
+        })
+    }
+}
+
+/// A session counted against its client's limit.
+#[derive(Debug)]
+pub struct ClientSlot {
+    ip: IpAddr,
+    sessions: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl Drop for ClientSlot {
+    fn drop(&mut self) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some(count) = sessions.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                sessions.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    const CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+    const OTHER: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+
+    #[test]
+    fn parses_rates() {
+        let rate: Rate = "3/60".parse().unwrap();
+        assert_eq!(rate.count, 3);
+        assert_eq!(rate.period, Duration::from_secs(60));
+        assert_eq!(rate.to_string(), "3/60");
+    }
+
+    #[test]
+    fn rejects_malformed_rates() {
+        for rate in ["3", "0/60", "3/0", "x/60", "3/-1", "3/1.5", "/60"] {
+            assert!(rate.parse::<Rate>().is_err(), "{rate}");
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn limiter_rejects_bursts_and_refills() {
+        let limiter = RateLimiter::new("2/10".parse().unwrap());
+        assert!(limiter.check(CLIENT));
+        assert!(limiter.check(CLIENT));
+        assert!(!limiter.check(CLIENT));
+        // other clients have buckets of their own
+        assert!(limiter.check(OTHER));
+
+        // one token comes back every five seconds
+        tokio::time::advance(Duration::from_secs(4)).await;
+        assert!(!limiter.check(CLIENT));
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(limiter.check(CLIENT));
+        assert!(!limiter.check(CLIENT));
+
+        // but no more than the burst size accumulates
+        tokio::time::advance(Duration::from_secs(60)).await;
+        assert!(limiter.check(CLIENT));
+        assert!(limiter.check(CLIENT));
+        assert!(!limiter.check(CLIENT));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn throttle_holds_output_until_paid_off() {
+        let mut throttle = OutputThrottle::new(100);
+        throttle.consume(100);
+        assert_eq!(throttle.ready_at(), None);
+        throttle.consume(50);
+        assert_eq!(
+            throttle.ready_at(),
+            Some(Instant::now() + Duration::from_millis(500))
+        );
+        tokio::time::advance(Duration::from_millis(500)).await;
+        assert_eq!(throttle.ready_at(), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn throttle_refills_up_to_one_second() {
+        let mut throttle = OutputThrottle::new(100);
+        // idling doesn't save up more than a second's worth
+        tokio::time::advance(Duration::from_secs(10)).await;
+        throttle.consume(150);
+        assert_eq!(
+            throttle.ready_at(),
+            Some(Instant::now() + Duration::from_millis(500))
+        );
+        // however the output is split, the debt is the same
+        let mut split = OutputThrottle::new(100);
+        for _ in 0..15 {
+            split.consume(10);
+        }
+        assert_eq!(split.ready_at(), throttle.ready_at());
+        tokio::time::advance(Duration::from_millis(250)).await;
+        assert_eq!(
+            throttle.ready_at(),
+            Some(Instant::now() + Duration::from_millis(250))
+        );
+    }
+
+    #[test]
+    fn client_limiter_caps_sessions_per_address() {
+        let limiter = ClientLimiter::new(2);
+        let first = limiter.acquire(CLIENT).unwrap();
+        let _second = limiter.acquire(CLIENT).unwrap();
+        assert!(limiter.acquire(CLIENT).is_none());
+        let _other = limiter.acquire(OTHER).unwrap();
+        drop(first);
+        let _third = limiter.acquire(CLIENT).unwrap();
+        assert!(limiter.acquire(CLIENT).is_none());
+    }
+
+    #[test]
+    fn client_limiter_forgets_idle_addresses() {
+        let limiter = ClientLimiter::new(1);
+        drop(limiter.acquire(CLIENT).unwrap());
+        assert!(limiter.sessions.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,98 @@
+use std::collections::VecDeque;
+
+use axum::body::Bytes;
+
+/// The most recent output of a session, replayed to clients that reattach.
+#[derive(Debug)]
+pub struct Scrollback {
+    data: VecDeque<u8>,
+    capacity: usize,
+}
+
+impl Scrollback {
+    /// Creates a buffer keeping the last `capacity` bytes.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            data: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    pub fn push(&mut self, output: &[u8]) {
+        let output = &output[output.len().saturating_sub(self.capacity)..];
+        let overflow = (self.data.len() + output.len()).saturating_sub(self.capacity);
+        self.data.drain(..overflow);
+        self.data.extend(output);
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Changes how many bytes are kept, dropping the oldest ones if the
+    /// buffer shrinks.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.data.drain(..self.data.len().saturating_sub(capacity));
+    }
+
+    /// Returns everything retained, oldest first.
+    pub fn contents(&self) -> Bytes {
+        let (front, back) = self.data.as_slices();
+        [front, back].concat().into()
+    }
+
+    /// Returns the last `len` bytes retained, or all of them if there are
+    /// fewer.
+    pub fn tail(&self, len: usize) -> Bytes {
+        let start = self.data.len().saturating_sub(len);
+        self.data.range(start..).copied().collect::<Vec<_>>().into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_newest_bytes() {
+        let mut scrollback = Scrollback::new(8);
+        scrollback.push(b"hello");
+        assert_eq!(scrollback.contents(), "hello");
+        scrollback.push(b" world");
+        assert_eq!(scrollback.contents(), "lo world");
+        // a chunk larger than the whole buffer keeps only its end
+        scrollback.push(b"0123456789");
+        assert_eq!(scrollback.contents(), "23456789");
+    }
+
+    #[test]
+    fn keeps_nothing_without_capacity() {
+        let mut scrollback = Scrollback::new(0);
+        scrollback.push(b"hello");
+        assert_eq!(scrollback.contents(), "");
+    }
+
+    #[test]
+    fn shrinking_drops_the_oldest_bytes() {
+        let mut scrollback = Scrollback::new(8);
+        scrollback.push(b"abcdefgh");
+        scrollback.set_capacity(3);
+        assert_eq!(scrollback.capacity(), 3);
+        assert_eq!(scrollback.contents(), "fgh");
+        scrollback.set_capacity(5);
+        scrollback.push(b"ij");
+        assert_eq!(scrollback.contents(), "fghij");
+    }
+
+    #[test]
+    fn tail_returns_the_last_bytes() {
+        let mut scrollback = Scrollback::new(8);
+        scrollback.push(b"abcdefgh");
+        // wrap the ring buffer so the data spans both of its slices
+        scrollback.push(b"ij");
+        assert_eq!(scrollback.tail(3), "hij");
+        assert_eq!(scrollback.tail(0), "");
+        assert_eq!(scrollback.tail(usize::MAX), "cdefghij");
+    }
+}
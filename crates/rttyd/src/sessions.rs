@@ -0,0 +1,198 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+use axum::extract::ws::WebSocket;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+type Sessions = Arc<Mutex<HashMap<Uuid, SessionHandle>>>;
+
+/// Ways of reaching a live session from outside its task.
+#[derive(Debug, Clone)]
+struct SessionHandle {
+    input: mpsc::Sender<Vec<u8>>,
+    attach: mpsc::Sender<WebSocket>,
+    view: mpsc::Sender<WebSocket>,
+    /// Set while the session has no client and waits for one to reattach.
+    detached: Arc<AtomicBool>,
+}
+
+/// Live sessions, keyed by session id.
+#[derive(Debug, Default)]
+pub struct SessionRegistry {
+    sessions: Sessions,
+}
+
+#[derive(Debug)]
+pub enum AttachError {
+    /// No session has the given id.
+    Unknown,
+    /// The session still has a client.
+    Attached,
+}
+
+impl SessionRegistry {
+    /// Adds a session. Input sent to it through [`SessionRegistry::input`]
+    /// and sockets passed to [`SessionRegistry::attach`] or
+    /// [`SessionRegistry::view`] arrive on the returned registration until it
+    /// is dropped.
+    pub fn register(&self, id: Uuid, buffer: usize) -> Registration {
+        let (input_tx, input) = mpsc::channel(buffer);
+        let (attach_tx, attach) = mpsc::channel(1);
+        let (view_tx, view) = mpsc::channel(1);
+        let detached = Arc::new(AtomicBool::new(false));
+        let handle = SessionHandle {
+            input: input_tx,
+            attach: attach_tx,
+            view: view_tx,
+            detached: detached.clone(),
+        };
+        self.sessions.lock().unwrap().insert(id, handle);
+        Registration {
+            id,
+            sessions: self.sessions.clone(),
+            input,
+            attach,
+            view,
+            detached,
+        }
+    }
+
+    /// Returns a sender feeding input to the session with the given id.
+    pub fn input(&self, id: Uuid) -> Option<mpsc::Sender<Vec<u8>>> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .get(&id)
+            .map(|handle| handle.input.clone())
+    }
+
+    /// Claims a detached session for a new client, returning the sender to
+    /// hand it the client's socket on.
+    pub fn attach(&self, id: Uuid) -> Result<(mpsc::Sender<WebSocket>, Claim), AttachError> {
+        let sessions = self.sessions.lock().unwrap();
+        let handle = sessions.get(&id).ok_or(AttachError::Unknown)?;
+        handle
+            .detached
+            .compare_exchange(true, false, Ordering::SeqCst, Ordering::SeqCst)
+            .map_err(|_| AttachError::Attached)?;
+        let claim = Claim(Some(handle.detached.clone()));
+        Ok((handle.attach.clone(), claim))
+    }
+
+    /// Returns a sender handing the session the sockets of read-only viewers.
+    pub fn view(&self, id: Uuid) -> Option<mpsc::Sender<WebSocket>> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .get(&id)
+            .map(|handle| handle.view.clone())
+    }
+}
+
+/// A detached session claimed by [`SessionRegistry::attach`]. Unless kept
+/// once the client's socket has been handed over, dropping it hands the
+/// session back so another client may reattach.
+#[derive(Debug)]
+pub struct Claim(Option<Arc<AtomicBool>>);
+
+impl Claim {
+    /// Keeps the session attached for good.
+    pub fn keep(mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        if let Some(detached) = self.0.take() {
+            detached.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+/// A session's entry in the registry, removed when dropped.
+pub struct Registration {
+    id: Uuid,
+    sessions: Sessions,
+    /// Input from the control API.
+    pub input: mpsc::Receiver<Vec<u8>>,
+    /// Sockets of clients reattaching to the session.
+    pub attach: mpsc::Receiver<WebSocket>,
+    /// Sockets of clients joining a shared session as viewers.
+    pub view: mpsc::Receiver<WebSocket>,
+    detached: Arc<AtomicBool>,
+}
+
+impl Registration {
+    /// Marks the session as waiting for a client to reattach, or not.
+    pub fn set_detached(&self, detached: bool) {
+        self.detached.store(detached, Ordering::SeqCst);
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.sessions.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attaches_only_while_detached() {
+        let registry = SessionRegistry::default();
+        let id = Uuid::new_v4();
+        let registration = registry.register(id, 1);
+        assert!(matches!(registry.attach(id), Err(AttachError::Attached)));
+        registration.set_detached(true);
+        let (_, claim) = registry.attach(id).unwrap();
+        assert!(matches!(registry.attach(id), Err(AttachError::Attached)));
+        claim.keep();
+        assert!(matches!(registry.attach(id), Err(AttachError::Attached)));
+    }
+
+    #[test]
+    fn dropped_claim_releases_session() {
+        let registry = SessionRegistry::default();
+        let id = Uuid::new_v4();
+        let registration = registry.register(id, 1);
+        registration.set_detached(true);
+        let (_, claim) = registry.attach(id).unwrap();
+        drop(claim);
+        assert!(registry.attach(id).is_ok());
+    }
+
+    #[test]
+    fn dropped_registration_unregisters() {
+        let registry = SessionRegistry::default();
+        let id = Uuid::new_v4();
+        let registration = registry.register(id, 1);
+        assert!(registry.input(id).is_some());
+        assert!(matches!(
+            registry.attach(Uuid::new_v4()),
+            Err(AttachError::Unknown)
+        ));
+        drop(registration);
+        assert!(registry.input(id).is_none());
+        assert!(registry.view(id).is_none());
+        assert!(matches!(registry.attach(id), Err(AttachError::Unknown)));
+    }
+
+    #[test]
+    fn viewers_join_attached_sessions() {
+        let registry = SessionRegistry::default();
+        let id = Uuid::new_v4();
+        let _registration = registry.register(id, 1);
+        assert!(matches!(registry.attach(id), Err(AttachError::Attached)));
+        assert!(registry.view(id).is_some());
+        assert!(registry.view(Uuid::new_v4()).is_none());
+    }
+}
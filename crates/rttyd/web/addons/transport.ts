@@ -2,19 +2,48 @@ import { Terminal, type IDisposable, type ITerminalAddon } from 'xterm';
 import { TrzszFilter } from './trzsz/filter';
 import * as Base64 from "base64-js";
 
+// "service restart": sent when the server wants the client to offer reconnecting
+const RESTART_CLOSE_CODE = 1012;
+// the connection dropped without a close frame
+const ABNORMAL_CLOSE_CODE = 1006;
+
 function createSocket() {
-  const endpoint = `${window.location.origin.replace(/^http/, 'ws')}/ws`;
+  const endpoint = new URL('/ws', window.location.origin.replace(/^http/, 'ws'));
+  const params = new URLSearchParams(window.location.search);
+  for (const name of ['token', 'session']) {
+    const value = params.get(name);
+    if (value != null) {
+      endpoint.searchParams.set(name, value);
+    }
+  }
   const socket = new WebSocket(endpoint);
   socket.binaryType = 'arraybuffer';
 
   return socket;
 }
 
+// the session to reattach to is kept in the page's URL, so reloading the page
+// picks it up again too
+function sessionParam(): string | null {
+  return new URLSearchParams(window.location.search).get('session');
+}
+
+function setSessionParam(id: string | null) {
+  const url = new URL(window.location.href);
+  if (id == null) {
+    url.searchParams.delete('session');
+  } else {
+    url.searchParams.set('session', id);
+  }
+  window.history.replaceState(null, '', url);
+}
+
 export class TransportAddon implements ITerminalAddon {
   private socket?: WebSocket
   private terminal?: Terminal;
   private trzsz?: TrzszFilter;
   private disposables: IDisposable[] = [];
+  private opened = false;
 
   public activate(terminal: Terminal): void {
     terminal.clear();
@@ -22,6 +51,7 @@ export class TransportAddon implements ITerminalAddon {
 
     this.socket = createSocket();
     this.terminal = terminal;
+    this.opened = false;
 
     const writeToTerminal = (data: string | ArrayBuffer | Uint8Array | Blob) => {
       if (data instanceof Blob) {
@@ -34,6 +64,9 @@ export class TransportAddon implements ITerminalAddon {
         terminal.write(typeof data === "string" ? data : new Uint8Array(data));
       }
     };
+    // text goes out as-is under `1;`, since text frames carry UTF-8 anyway; bytes
+    // that may not be valid UTF-8 (e.g. trzsz uploads) go in binary frames, so
+    // input never needs the base64 `0;` form
     const sendToServer = (data: string | Uint8Array) => {
       if (!this.checkOpenSocket()) return;
       if (typeof data === 'string') {
@@ -50,12 +83,24 @@ export class TransportAddon implements ITerminalAddon {
     });
 
     this.disposables = [];
-    this.disposables.push(addSocketListener(this.socket, 'open', () => this.onSocketOpen()));
-    this.disposables.push(addSocketListener(this.socket, 'close', () => {
+    this.disposables.push(addSocketListener(this.socket, 'open', () => {
+      this.opened = true;
+      this.onSocketOpen();
+    }));
+    this.disposables.push(addSocketListener(this.socket, 'close', (ev) => {
+      if (ev.code === ABNORMAL_CLOSE_CODE && this.reattach()) return;
+      setSessionParam(null);
+      if (ev.code === RESTART_CLOSE_CODE) {
+        this.dispose();
+        this.offerRestart();
+        return;
+      }
       setTimeout(() => this.terminal?.write('\r\n\x1B[90mDisconnected from server.\x1B[0m'), 200);
       this.dispose();
     }));
     this.disposables.push(addSocketListener(this.socket, 'error', () => {
+      if (this.reattach()) return;
+      setSessionParam(null);
       setTimeout(() => this.terminal?.write('\r\n\x1B[90mConnection failed with error.\x1B[0m'), 200);
       this.dispose();
     }));
@@ -92,12 +137,50 @@ export class TransportAddon implements ITerminalAddon {
         this.trzsz?.processServerOutput(Base64.toByteArray(data.slice(2)));
       } else if (data.startsWith('1;')) {
         this.trzsz?.processServerOutput(data.slice(2));
+      } else if (data.startsWith('5;')) {
+        document.title = data.slice(2);
+      } else if (data.startsWith('8;')) {
+        // the server may have clamped our size; follow what the PTY uses
+        const [cols, rows] = data.slice(2).split(';').map(Number);
+        if (this.terminal && (this.terminal.cols !== cols || this.terminal.rows !== rows)) {
+          this.terminal.resize(cols, rows);
+        }
+      } else if (data.startsWith('c;')) {
+        // the session can be reattached to (--detach-on-disconnect) or shared (--shared)
+        setSessionParam(data.slice(2));
+      } else if (data.startsWith('9;')) {
+        this.terminal?.write(`\r\nConnection to process lost: ${data.slice(2)}\r\n`);
       }
     } else {
       this.trzsz?.processServerOutput(data);
     }
   }
 
+  // a lost connection to a session the server keeps is picked up again, once it
+  // had been established; returns whether that is being tried
+  private reattach(): boolean {
+    const terminal = this.terminal;
+    if (!this.opened || sessionParam() == null || terminal == null) return false;
+    this.dispose();
+    terminal.write('\r\n\x1B[90mConnection lost, reattaching...\x1B[0m');
+    setTimeout(() => this.activate(terminal), 1000);
+    return true;
+  }
+
+  // the command exited under `--on-exit reconnect`; start it again on Enter
+  private offerRestart(): void {
+    const terminal = this.terminal;
+    if (terminal == null) return;
+    terminal.write('\r\n\x1B[90mPress Enter to restart.\x1B[0m');
+    terminal.focus();
+    const listener = terminal.onData((data) => {
+      if (data === '\r') {
+        listener.dispose();
+        this.activate(terminal);
+      }
+    });
+  }
+
   private sendResize(): void {
     if (!this.checkOpenSocket()) return;
     if (this.terminal == null) return;
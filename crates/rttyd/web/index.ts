@@ -23,3 +23,12 @@ fitAddon.fit();
 window.addEventListener('resize', () => {
   fitAddon.fit();
 });
+
+fetch('/config')
+  .then((res) => res.json())
+  .then((config: { title?: string }) => {
+    if (config.title) {
+      document.title = config.title;
+    }
+  })
+  .catch((err) => console.warn('Failed to load config', err));